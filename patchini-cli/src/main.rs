@@ -0,0 +1,216 @@
+//! Headless frontend for the patch engine, for release pipelines and remote installs.
+//!
+//! Exit codes:
+//! - 0: success
+//! - 1: the operation failed
+//! - 2: invalid command line
+//! - 3: the patch file is corrupt or made by a newer version
+//! - 4: a file is used by another process or access was denied
+//! - 5: not enough disk space
+//! - 6: the target doesn't match the version the patch was made from or has files the patch
+//!   would add, nothing was changed
+//! - 7: `apply --dry-run` found applying would fail
+//! - 130: cancelled with Ctrl-C, an apply is rolled back first
+
+use clap::{Parser, Subcommand};
+use patchini::{ApplyOptions, CancelToken, ConflictPolicy, ConflictRule, CreateOptions, Error, Event, ExistingFiles, Progress};
+use std::io::{stderr, stdin, IsTerminal, Write};
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+const EXIT_FAILURE: u8 = 1;
+const EXIT_CORRUPT_PATCH: u8 = 3;
+const EXIT_ACCESS_DENIED: u8 = 4;
+const EXIT_DISK_FULL: u8 = 5;
+const EXIT_SOURCE_MISMATCH: u8 = 6;
+const EXIT_WOULD_FAIL: u8 = 7;
+const EXIT_CANCELLED: u8 = 130;
+
+#[derive(Parser)]
+#[command(name = "patchini", version, about = "Create and apply .patchini patches")]
+struct Cli {
+    /// Don't print progress, only errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a patch going from OLD to NEW
+    Create {
+        /// Directory with the current version
+        old: String,
+        /// Directory with the updated version
+        new: String,
+        /// zstd compression level, negative values trade ratio for speed
+        #[arg(short, long, default_value_t = 3, allow_negative_numbers = true)]
+        level: i32,
+        /// Where to write the patch
+        #[arg(short, long, default_value = "patch.patchini")]
+        output: String,
+        /// Also store the full new version of diffed files up to this many bytes, apply falls back to it when the local file was modified
+        #[arg(long, value_name = "BYTES")]
+        full_copy_max_size: Option<u64>,
+        /// Also store the full new version of diffed files matching this glob, whatever their size. Can be repeated
+        #[arg(long = "full-copy", value_name = "GLOB")]
+        full_copy_globs: Vec<String>,
+    },
+    /// Apply PATCH onto the TARGET directory
+    Apply {
+        /// Directory to update
+        target: String,
+        /// Patch file to apply, - to read it from stdin
+        patch: String,
+        /// Print what applying would change and how much disk space it needs, without writing anything. Exits with 7 if applying would fail
+        #[arg(long)]
+        dry_run: bool,
+        /// What to do with files the patch adds that already exist: overwrite (after backing them up), skip or fail
+        #[arg(long, default_value_t = ExistingFiles::Overwrite)]
+        existing: ExistingFiles,
+        /// What to do with files that don't match the version the patch was made from: overwrite (with a full copy from the patch, failing without one), fail, skip or keep (and list them in backup/conflicts.txt)
+        #[arg(long, default_value_t = ConflictPolicy::Overwrite)]
+        conflicts: ConflictPolicy,
+        /// Use another conflict policy for files matching a glob, as GLOB=POLICY. Can be repeated, the first match wins
+        #[arg(long = "conflict", value_name = "GLOB=POLICY")]
+        conflict_rules: Vec<ConflictRule>,
+    },
+    /// Undo the last patch applied to the TARGET directory
+    Rollback {
+        /// Directory to restore
+        target: String,
+    },
+    /// List what PATCH removes, adds and diffs
+    Inspect {
+        /// Patch file to inspect
+        patch: String,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that PATCH can be read to the end
+    Verify {
+        /// Patch file to verify
+        patch: String,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::from(match e {
+                Error::CorruptPatch { .. } | Error::UnsupportedFormat { .. } => EXIT_CORRUPT_PATCH,
+                Error::Locked { .. } | Error::PermissionDenied { .. } => EXIT_ACCESS_DENIED,
+                Error::DiskFull { .. } | Error::NotEnoughSpace { .. } => EXIT_DISK_FULL,
+                Error::SourceMismatch { .. } | Error::FileExists { .. } => EXIT_SOURCE_MISMATCH,
+                Error::Cancelled => EXIT_CANCELLED,
+                _ => EXIT_FAILURE,
+            })
+        }
+    }
+}
+
+/// Prints events as log lines on stdout, and a percentage on stderr when it's a terminal.
+struct Printer {
+    quiet: bool,
+    percent: Option<u64>,
+}
+
+impl Progress for Printer {
+    fn event(&mut self, event: &Event) {
+        match event {
+            Event::BytesProcessed { done, total } => {
+                if self.quiet || !stderr().is_terminal() { return }
+                let percent = (done * 100).checked_div(*total).unwrap_or(100);
+                if self.percent != Some(percent) {
+                    self.percent = Some(percent);
+                    eprint!("\r{percent:>3}%");
+                    let _ = stderr().flush();
+                }
+            }
+            Event::Warning(_) => {
+                self.clear_percent();
+                eprintln!("{event}");
+            }
+            _ => if !self.quiet {
+                self.clear_percent();
+                println!("{event}");
+            }
+        }
+    }
+}
+
+impl Printer {
+    fn clear_percent(&mut self) {
+        if self.percent.take().is_some() {
+            eprint!("\r    \r");
+        }
+    }
+}
+
+/// Makes Ctrl-C stop at the next file or chunk, so apply can roll back instead of leaving a broken
+/// install. Only the commands checking the token install it, Ctrl-C quits the others right away.
+fn cancel_on_ctrl_c(printer: &mut Printer) -> CancelToken {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGINT, interrupted.clone()) {
+        printer.event(&Event::Warning(format!("Couldn't register Ctrl-C handler, Ctrl-C won't roll back: {e}")));
+    }
+    CancelToken::from(interrupted)
+}
+
+/// Runs the command, a successful one can still end with a nonzero exit code.
+fn run(cli: Cli) -> patchini::Result<ExitCode> {
+    let mut printer = Printer { quiet: cli.quiet, percent: None };
+    let result = match cli.command {
+        Command::Create { old, new, level, output, full_copy_max_size, full_copy_globs } => {
+            let options = CreateOptions { level, full_copy_max_size, full_copy_globs };
+            let cancel = cancel_on_ctrl_c(&mut printer);
+            patchini::create_patch(old, new, &options, output, &mut printer, &cancel).map(|_| ExitCode::SUCCESS)
+        }
+        Command::Apply { target, patch, dry_run: true, existing, conflicts, conflict_rules } => {
+            let options = ApplyOptions { existing_files: existing, conflicts, conflict_rules };
+            let cancel = cancel_on_ctrl_c(&mut printer);
+            let dry_run = if patch == "-" {
+                patchini::dry_run_patch_from(target, stdin().lock(), &options, &mut printer, &cancel)?
+            } else {
+                patchini::dry_run_patch(target, patch, &options, &mut printer, &cancel)?
+            };
+            printer.clear_percent();
+            print!("{dry_run}");
+            Ok(if dry_run.would_fail() { ExitCode::from(EXIT_WOULD_FAIL) } else { ExitCode::SUCCESS })
+        }
+        Command::Apply { target, patch, existing, conflicts, conflict_rules, .. } => {
+            let options = ApplyOptions { existing_files: existing, conflicts, conflict_rules };
+            let cancel = cancel_on_ctrl_c(&mut printer);
+            let result = if patch == "-" {
+                patchini::apply_patch_from(target, stdin().lock(), None, &options, &mut printer, &cancel)
+            } else {
+                patchini::apply_patch(target, patch, &options, &mut printer, &cancel)
+            };
+            result.map(|_| ExitCode::SUCCESS)
+        }
+        Command::Rollback { target } => patchini::rollback_patch(target, &mut printer).map(|_| ExitCode::SUCCESS),
+        Command::Inspect { patch, json } => {
+            let inspection = patchini::inspect_patch(patch)?;
+            if json {
+                println!("{}", inspection.to_json());
+            } else {
+                print!("{inspection}");
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Verify { patch } => {
+            patchini::verify_patch(patch)?;
+            if !cli.quiet { println!("Patch is valid") }
+            Ok(ExitCode::SUCCESS)
+        }
+    };
+    printer.clear_percent();
+    result
+}
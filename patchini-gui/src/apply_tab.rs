@@ -1,5 +1,7 @@
 use crate::ids;
-use crate::patch::apply_patch;
+use crate::main_window::log_event;
+use patchini::{apply_patch, ApplyOptions, CancelToken, Error, Event};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use winsafe::co::SW;
 use winsafe::{self as w, co, gui, prelude::*, HWND};
@@ -14,7 +16,8 @@ pub struct ApplyTab {
     edit_patch: gui::Edit,
     btn_patch: gui::Button,
     btn_apply: gui::Button,
-    apply_log: gui::Edit
+    apply_log: gui::Edit,
+    cancel: Arc<Mutex<Option<CancelToken>>>
 }
 
 impl AsRef<gui::WindowControl> for ApplyTab {
@@ -38,7 +41,9 @@ impl ApplyTab {
         let btn_apply  = gui::Button::new_dlg(&wnd, ids::BTN_APPLY, dont_move);
         let apply_log = gui::Edit::new_dlg(&wnd, ids::TXT_APPLY, dont_move);
 
-        let new_self = Self { wnd, _label_path, edit_path, btn_path, _label_patch, edit_patch, btn_patch, btn_apply, apply_log };
+        let cancel = Arc::new(Mutex::new(None));
+
+        let new_self = Self { wnd, _label_path, edit_path, btn_path, _label_patch, edit_patch, btn_patch, btn_apply, apply_log, cancel };
         new_self.events();
         new_self
     }
@@ -109,27 +114,42 @@ impl ApplyTab {
         let self2 = self.clone();
         self2.btn_apply.clone().on().bn_clicked({
             move || -> w::AnyResult<()> {
+                // While running, the button cancels instead
+                if let Some(cancel) = self2.cancel.lock().unwrap().as_ref() {
+                    cancel.cancel();
+                    return Ok(());
+                }
                 std::thread::spawn({
                     *crate::main_window::EPOCH.lock().unwrap() = Some(Instant::now());
+                    *crate::main_window::PROGRESS.lock().unwrap() = None;
                     self2.switch_view(true);
-                    self2.btn_apply.hwnd().EnableWindow(false);
                     let old_path = self2.edit_path.text().map_err(|_| "Couldn't get old path")?.to_string();
                     let new_path = self2.edit_patch.text().map_err(|_| "Couldn't get new path")?.to_string();
+                    self2.apply_log.set_text("").map_err(|_| "Couldn't clear text")?;
+                    let _ = self2.btn_apply.hwnd().SetWindowText("Cancel");
+                    let cancel = CancelToken::new();
+                    *self2.cancel.lock().unwrap() = Some(cancel.clone());
                     let self3 = self2.clone();
                     move || {
-                        match apply_patch(old_path, new_path, &self3.apply_log) {
+                        match apply_patch(old_path, new_path, &ApplyOptions::default(), &mut |event: &Event| log_event(&self3.apply_log, event), &cancel) {
                             Ok(_) => {
                                 *crate::main_window::EPOCH.lock().unwrap() = None;
                                 HWND::NULL.MessageBox(
                                     "Patch applied successfully", "Success", co::MB::ICONINFORMATION).unwrap()
                             }
-                            Err(str) => {
+                            Err(Error::Cancelled) => {
+                                *crate::main_window::EPOCH.lock().unwrap() = None;
+                                HWND::NULL.MessageBox(
+                                    "Patch cancelled, the original files were restored", "Cancelled", co::MB::ICONINFORMATION).unwrap()
+                            }
+                            Err(e) => {
                                 *crate::main_window::EPOCH.lock().unwrap() = None;
                                 HWND::NULL.MessageBox(
-                                    &str, "Error", co::MB::ICONWARNING).unwrap()
+                                    &e.to_string(), "Error", co::MB::ICONWARNING).unwrap()
                             }
                         };
-                        self3.btn_apply.hwnd().EnableWindow(true);
+                        *self3.cancel.lock().unwrap() = None;
+                        let _ = self3.btn_apply.hwnd().SetWindowText("Apply");
                         self3.switch_view(false);
                     }
                 });
@@ -1,5 +1,7 @@
 use crate::ids;
-use crate::patch::create_patch;
+use crate::main_window::log_event;
+use patchini::{create_patch, CancelToken, CreateOptions, Error, Event};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use winsafe::co::SW;
 use winsafe::{self as w, co, gui, msg, prelude::*, AnyResult, HWND};
@@ -15,7 +17,8 @@ pub struct CreateTab {
     btn_new: gui::Button,
     track_lvl: gui::Trackbar,
     btn_create: gui::Button,
-    edit_log: gui::Edit
+    edit_log: gui::Edit,
+    cancel: Arc<Mutex<Option<CancelToken>>>
 }
 
 impl AsRef<gui::WindowControl> for CreateTab { // we must implement AsRef so this window can be used as a tab
@@ -41,7 +44,9 @@ impl CreateTab {
         let btn_create  = gui::Button::new_dlg(&wnd, ids::BTN_CREATE, dont_move);
         let edit_log = gui::Edit::new_dlg(&wnd, ids::TXT_CREATE, dont_move);
 
-        let new_self = Self { wnd, _label_old, edit_old, btn_old, _label_new, edit_new, btn_new, track_lvl, btn_create, edit_log };
+        let cancel = Arc::new(Mutex::new(None));
+
+        let new_self = Self { wnd, _label_old, edit_old, btn_old, _label_new, edit_new, btn_new, track_lvl, btn_create, edit_log, cancel };
         new_self.events();
         new_self
     }
@@ -119,30 +124,57 @@ impl CreateTab {
         let self2 = self.clone();
         self2.btn_create.clone().on().bn_clicked({
             move || -> AnyResult<()> {
+                // While running, the button cancels instead
+                if let Some(cancel) = self2.cancel.lock().unwrap().as_ref() {
+                    cancel.cancel();
+                    return Ok(());
+                }
+                let files = w::CoCreateInstance::<w::IFileSaveDialog>(
+                    &co::CLSID::FileSaveDialog,
+                    None::<&w::IUnknown>,
+                    co::CLSCTX::INPROC_SERVER,
+                )?;
+                files.SetFileTypes(&[("Patchini patch", "*.patchini")])?;
+                files.SetDefaultExtension("patchini")?;
+                files.SetFileName("patch.patchini")?;
+                if !files.Show(self2.wnd.hwnd())? {
+                    return Ok(());
+                }
+                let output = files.GetResult()?.GetDisplayName(co::SIGDN::FILESYSPATH)?;
                 std::thread::spawn({
                     *crate::main_window::EPOCH.lock().unwrap() = Some(Instant::now());
+                    *crate::main_window::PROGRESS.lock().unwrap() = None;
                     self2.switch_view(true);
-                    self2.btn_create.hwnd().EnableWindow(false);
                     let old_path = self2.edit_old.text().map_err(|_| "Couldn't get old path")?.to_string();
                     let new_path = self2.edit_new.text().map_err(|_| "Couldn't get new path")?.to_string();
                     let mut lvl = self2.track_lvl.pos() as i32 - 8;
                     if lvl <= 0 { lvl -= 1 };
+                    self2.edit_log.set_text("").map_err(|_| "Couldn't clear text")?;
+                    let _ = self2.btn_create.hwnd().SetWindowText("Cancel");
+                    let cancel = CancelToken::new();
+                    *self2.cancel.lock().unwrap() = Some(cancel.clone());
                     let self3 = self2.clone();
                     move || {
-                        match create_patch(old_path, new_path, lvl, &self3.edit_log) {
+                        match create_patch(old_path, new_path, &CreateOptions { level: lvl, ..Default::default() }, output, &mut |event: &Event| log_event(&self3.edit_log, event), &cancel) {
                             Ok(_) => {
                                 *crate::main_window::EPOCH.lock().unwrap() = None;
                                 HWND::NULL.MessageBox(
                                     "Patch created successfully", "Success", co::MB::ICONINFORMATION).unwrap()
                             }
-                            Err(str) => {
+                            Err(Error::Cancelled) => {
+                                *crate::main_window::EPOCH.lock().unwrap() = None;
+                                HWND::NULL.MessageBox(
+                                    "Patch creation cancelled", "Cancelled", co::MB::ICONINFORMATION).unwrap()
+                            }
+                            Err(e) => {
                                 *crate::main_window::EPOCH.lock().unwrap() = None;
                                 HWND::NULL.MessageBox(
-                                    &str, "Error", co::MB::ICONWARNING).unwrap()
+                                    &e.to_string(), "Error", co::MB::ICONWARNING).unwrap()
                             }
                         };
 
-                        self3.btn_create.hwnd().EnableWindow(true);
+                        *self3.cancel.lock().unwrap() = None;
+                        let _ = self3.btn_create.hwnd().SetWindowText("Create");
                         self3.switch_view(false);
                     }
                 });
@@ -1,5 +1,4 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-mod patch;
 mod main_window;
 mod ids;
 mod create_tab;
@@ -1,9 +1,10 @@
 use crate::apply_tab::ApplyTab;
 use crate::create_tab::CreateTab;
+use patchini::Event;
 use std::sync::{LazyLock, Mutex};
 use std::time::Instant;
-use winsafe::prelude::{GuiParent, GuiWindow};
-use winsafe::{co, gui, AnyResult};
+use winsafe::prelude::{user_Hwnd, GuiParent, GuiWindow};
+use winsafe::{co, gui, msg, AnyResult, WString};
 
 #[derive(Clone)]
 pub struct MainWindow {
@@ -12,6 +13,7 @@ pub struct MainWindow {
 }
 
 pub(crate) static EPOCH: LazyLock<Mutex<Option<Instant>>> = LazyLock::new(|| Mutex::new(None));
+pub(crate) static PROGRESS: LazyLock<Mutex<Option<(u64, u64)>>> = LazyLock::new(|| Mutex::new(None));
 
 impl MainWindow {
     pub fn new() -> Self {
@@ -65,7 +67,34 @@ impl MainWindow {
 
 fn update_status(status: &gui::StatusBar) -> AnyResult<()> {
     if let Some(x) = *EPOCH.lock().unwrap() {
-        status.parts().get(0).set_text(&x.elapsed().as_secs().to_string())?;
+        let elapsed = x.elapsed().as_secs();
+        let text = match *PROGRESS.lock().unwrap() {
+            Some((done, total)) if done > 0 && total > 0 => {
+                let left = elapsed * (total - done) / done;
+                format!("{}% - {elapsed}s elapsed, about {left}s left", done * 100 / total)
+            }
+            _ => elapsed.to_string()
+        };
+        status.parts().get(0).set_text(&text)?;
     }
     Ok(())
+}
+
+pub(crate) fn log_event(log: &gui::Edit, event: &Event) {
+    match event {
+        Event::BytesProcessed { done, total } => *PROGRESS.lock().unwrap() = Some((*done, *total)),
+        _ => log_info(log, &event.to_string())
+    }
+}
+
+/// Appends `text` as a line. The log gets long, so it's never read back, only its length in UTF-16
+/// units is asked for to put the caret at the end.
+fn log_info(log: &gui::Edit, text: &str) {
+    let i = log.hwnd().GetWindowTextLength().unwrap_or(0);
+    log.set_selection(i, i);
+    unsafe {
+        log.hwnd().SendMessage(msg::em::ReplaceSel {
+            can_be_undone: false, replacement_text: WString::from_str(format!("{text}\r\n"))
+        });
+    }
 }
\ No newline at end of file
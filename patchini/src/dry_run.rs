@@ -0,0 +1,265 @@
+use crate::cancel::CancelToken;
+use crate::error::{Error, Result};
+use crate::hash::{parse_list, FileKind, HashEntry};
+use crate::inspect::read_text;
+use crate::layout::{listed_paths, DiffOrder, EntryName};
+use crate::manifest::Manifest;
+use crate::options::{ApplyOptions, ConflictPolicy, ExistingFiles};
+use crate::patch::{check_sources, plan_moves, PlannedMove};
+use crate::progress::{Event, Phase, Progress};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs::{metadata, File};
+use std::io::Read;
+use std::path::Path;
+use tar::{Archive, EntryType};
+
+/// What apply would do to a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Extract a file that doesn't exist yet
+    Add,
+    /// Extract or move a file over an existing one, as [`ExistingFiles::Overwrite`] says
+    Overwrite,
+    /// Rebuild a file from its diffs
+    Patch,
+    /// Make an added file from a removed one with the same content, without extracting it. Existing
+    /// files in the way are reported as [`Action::Overwrite`]
+    Move,
+    /// Move a file to the backup dir
+    Remove,
+}
+
+/// A change apply would make, `size` is the size of the file once written, or its current size
+/// for removals.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedChange {
+    pub action: Action,
+    pub path: String,
+    pub size: u64,
+}
+
+/// A file that doesn't match the version the patch was made from, or is missing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub path: String,
+    /// What apply would do with it, [`ConflictPolicy::Fail`] when it would refuse to start. That's
+    /// also the case when the policy says to overwrite it but the patch has no full copy.
+    pub policy: ConflictPolicy,
+}
+
+/// Everything applying a patch would do to a directory, as returned by [`dry_run_patch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DryRun {
+    pub manifest: Manifest,
+    /// In the order apply would make them
+    pub changes: Vec<PlannedChange>,
+    /// Files to patch or remove that aren't there
+    pub missing: Vec<String>,
+    /// Files to patch or remove that don't match the version the patch was made from, including
+    /// missing files to patch
+    pub mismatched: Vec<Mismatch>,
+    /// Files the patch moves with no unmodified removed file left to make them from, apply would
+    /// fail on them
+    pub unmovable: Vec<String>,
+    /// Added or moved files that already exist and are left as they are, as
+    /// [`ExistingFiles::Skip`] says
+    pub skipped: Vec<String>,
+    /// Added or moved files that already exist, apply would refuse to start because of them as
+    /// [`ExistingFiles::Fail`] says
+    pub existing: Vec<String>,
+    /// Disk space apply needs on top of what the directory already uses
+    pub space_needed: u64,
+}
+
+impl DryRun {
+    /// Existing files that added or moved files would replace.
+    pub fn clobbered(&self) -> impl Iterator<Item = &str> {
+        self.changes.iter().filter(|x| x.action == Action::Overwrite).map(|x| x.path.as_str())
+    }
+
+    /// Whether apply would fail because of conflicting or existing files.
+    pub fn would_fail(&self) -> bool {
+        self.mismatched.iter().any(|x| x.policy == ConflictPolicy::Fail) || !self.unmovable.is_empty() || !self.existing.is_empty()
+    }
+}
+
+impl Display for DryRun {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (action, title) in [(Action::Add, "would add"), (Action::Overwrite, "would overwrite"), (Action::Patch, "would patch"), (Action::Move, "would move"), (Action::Remove, "would remove")] {
+            let changes: Vec<&PlannedChange> = self.changes.iter().filter(|x| x.action == action).collect();
+            writeln!(f, "{title}: {} files, {} bytes", changes.len(), changes.iter().map(|x| x.size).sum::<u64>())?;
+            for change in changes {
+                writeln!(f, "{:>12} {}", change.size, change.path)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "missing files: {}", self.missing.len())?;
+        for path in &self.missing {
+            writeln!(f, "{:>12} {path}", "")?;
+        }
+        writeln!(f, "\nmodified files: {}", self.mismatched.len())?;
+        for mismatch in &self.mismatched {
+            writeln!(f, "{:>12} {}", mismatch.policy, mismatch.path)?;
+        }
+        writeln!(f, "\nmoved files without an unmodified original: {}", self.unmovable.len())?;
+        for path in &self.unmovable {
+            writeln!(f, "{:>12} {path}", "")?;
+        }
+        writeln!(f, "\nexisting files left alone: {}", self.skipped.len())?;
+        for path in &self.skipped {
+            writeln!(f, "{:>12} {path}", "")?;
+        }
+        writeln!(f, "\nexisting files in the way: {}", self.existing.len())?;
+        for path in &self.existing {
+            writeln!(f, "{:>12} {path}", "")?;
+        }
+        writeln!(f, "\nextra disk space needed: {} bytes", self.space_needed)?;
+        if self.would_fail() {
+            writeln!(f, "\napply would fail")?;
+        }
+        Ok(())
+    }
+}
+
+/// Goes through `patch` like [`apply_patch`](crate::apply_patch) would with `options` on the
+/// directory `path`, without writing anything, not even the backup dir. Files the patch checks are
+/// hashed, which is reported to `progress`.
+pub fn dry_run_patch(path: String, patch: String, options: &ApplyOptions, progress: &mut dyn Progress, cancel: &CancelToken) -> Result<DryRun> {
+    if !metadata(&patch).is_ok_and(|x| x.is_file()) { return Err(Error::InvalidArgument("Patch file doesn't exist".to_string())) };
+    let patch_file = File::open(&patch).map_err(|e| Error::io(&patch, e))?;
+    dry_run_patch_from(path, patch_file, options, progress, cancel)
+}
+
+/// Same as [`dry_run_patch`], but reads the patch from any stream.
+pub fn dry_run_patch_from(path: String, patch: impl Read, options: &ApplyOptions, progress: &mut dyn Progress, cancel: &CancelToken) -> Result<DryRun> {
+    if !metadata(&path).is_ok_and(|x| x.is_dir()) { return Err(Error::InvalidArgument("Path to update doesn't exist or is not a directory".to_string())) };
+    let root = Path::new(&path);
+    let mut report = |event: &Event| progress.event(event);
+    let result = zstd::Decoder::new(patch).map_err(|e| Error::corrupt_io("couldn't start decompressing", e))?;
+    let mut a = Archive::new(result);
+    let mut manifest = None;
+    let mut dry_run = DryRun { manifest: Manifest::legacy(), changes: Vec::new(), missing: Vec::new(), mismatched: Vec::new(), unmovable: Vec::new(), skipped: Vec::new(), existing: Vec::new(), space_needed: 0 };
+    let mut targets = HashMap::<String, HashEntry>::new();
+    let mut diff_order = DiffOrder::default();
+    // Resolved once the target hashes tell which files have a full copy, like apply does
+    let mut mismatches = Option::<Vec<HashEntry>>::None;
+    let mut left_alone = HashSet::<String>::new();
+    let mut removed_sources = Vec::<HashEntry>::new();
+    let mut moved_away = HashSet::<String>::new();
+    for file in a.entries().map_err(|e| Error::corrupt_io("couldn't list entries", e))? {
+        cancel.check()?;
+        let mut file = file.map_err(|e| Error::corrupt_io("couldn't read entry", e))?;
+        if file.header().entry_type() == EntryType::Directory {
+            continue
+        }
+        let name = file.path().map_err(|e| Error::corrupt_io("couldn't get entry path", e))?
+            .to_str().ok_or(Error::corrupt("entry path isn't valid unicode"))?.to_string();
+        let entry = EntryName::parse(&name)?;
+        if manifest.is_none() {
+            let found = if entry == EntryName::Manifest { Manifest::parse(&read_text(&mut file, &name)?)? } else { Manifest::legacy() };
+            found.check_supported()?;
+            manifest = Some(found);
+            if entry == EntryName::Manifest {
+                continue
+            }
+        }
+        if !matches!(entry, EntryName::SourceHashes | EntryName::TargetHashes) && let Some(mismatches) = mismatches.take() {
+            resolve(&mut dry_run, mismatches, &targets, options, &mut left_alone);
+        }
+        match entry {
+            EntryName::Manifest => return Err(Error::corrupt("manifest isn't the first entry")),
+            EntryName::SourceHashes => {
+                report(&Event::PhaseStarted(Phase::Verifying));
+                let sources = parse_list(&read_text(&mut file, &name)?)?;
+                mismatches = Some(check_sources(root, &sources, &mut report, cancel)?.into_iter().cloned().collect());
+                removed_sources = sources.into_iter().filter(|x| x.kind == FileKind::Removed).collect();
+            }
+            EntryName::TargetHashes => targets = parse_list(&read_text(&mut file, &name)?)?.into_iter().map(|x| (x.path.clone(), x)).collect(),
+            EntryName::Added(path) => {
+                let action = if file_size(root, &path).is_some() { Action::Overwrite } else { Action::Add };
+                if is_written(&mut dry_run, root, &path, options) {
+                    dry_run.space_needed += file.size();
+                    dry_run.changes.push(PlannedChange { action, path, size: file.size() });
+                }
+            }
+            EntryName::Diff { path, chunk, .. } => {
+                if !diff_order.next(&path, chunk)? || left_alone.contains(&path) {
+                    continue
+                }
+                let existing = file_size(root, &path);
+                if existing.is_none() {
+                    dry_run.missing.push(path.clone());
+                }
+                // Originals are moved to the backup dir, the rebuilt files take new space.
+                // Patches made before hashes existed don't say how big it gets
+                let size = targets.get(&path).map(|x| x.size).or(existing).unwrap_or(0);
+                dry_run.space_needed += size;
+                dry_run.changes.push(PlannedChange { action: Action::Patch, path, size });
+            }
+            // Only used when the file doesn't match
+            EntryName::Full(_) => {}
+            EntryName::Moved => {
+                let moved = listed_paths(&read_text(&mut file, &name)?)?.into_iter().filter(|x| is_written(&mut dry_run, root, x, options)).collect();
+                // Apply can only move removed files that matched their hash
+                let mismatched: HashSet<&str> = dry_run.mismatched.iter().map(|x| x.path.as_str()).collect();
+                let (planned, unmovable) = plan_moves(moved, &targets, &removed_sources, |x| !mismatched.contains(x) && file_size(root, x).is_some())?;
+                for PlannedMove { path, from, take } in planned {
+                    let action = if file_size(root, &path).is_some() { Action::Overwrite } else { Action::Move };
+                    let size = targets.get(&path).map_or(0, |x| x.size);
+                    // A moved original takes no new space and isn't removed anymore
+                    if take {
+                        moved_away.insert(from);
+                    } else {
+                        dry_run.space_needed += size;
+                    }
+                    dry_run.changes.push(PlannedChange { action, path, size });
+                }
+                dry_run.unmovable = unmovable;
+            }
+            EntryName::Removed => {
+                for path in listed_paths(&read_text(&mut file, &name)?)?.into_iter().filter(|x| !left_alone.contains(x) && !moved_away.contains(x)) {
+                    match file_size(root, &path) {
+                        Some(size) => dry_run.changes.push(PlannedChange { action: Action::Remove, path, size }),
+                        None => dry_run.missing.push(path),
+                    }
+                }
+            }
+        }
+    }
+    if let Some(mismatches) = mismatches.take() {
+        resolve(&mut dry_run, mismatches, &targets, options, &mut left_alone);
+    }
+    dry_run.manifest = manifest.unwrap_or_else(Manifest::legacy);
+    Ok(dry_run)
+}
+
+/// Records what apply would do with each of `mismatches`, and which files it would leave alone.
+fn resolve(dry_run: &mut DryRun, mismatches: Vec<HashEntry>, targets: &HashMap<String, HashEntry>, options: &ApplyOptions, left_alone: &mut HashSet<String>) {
+    for entry in mismatches {
+        let full_copy = targets.get(&entry.path).is_some_and(|x| x.kind == FileKind::Hybrid);
+        let policy = options.conflict_policy(&entry.path).for_file(entry.kind == FileKind::Removed, full_copy);
+        if matches!(policy, ConflictPolicy::Skip | ConflictPolicy::Keep) {
+            left_alone.insert(entry.path.clone());
+        }
+        dry_run.mismatched.push(Mismatch { path: entry.path, policy });
+    }
+}
+
+/// Whether apply would write the added or moved `path`. When it exists and `options` don't say to
+/// overwrite it, it's recorded as skipped or in the way instead.
+fn is_written(dry_run: &mut DryRun, root: &Path, path: &str, options: &ApplyOptions) -> bool {
+    if !root.join(path).exists() {
+        return true;
+    }
+    match options.existing_files {
+        ExistingFiles::Overwrite => return true,
+        ExistingFiles::Skip => dry_run.skipped.push(path.to_string()),
+        ExistingFiles::Fail => dry_run.existing.push(path.to_string()),
+    }
+    false
+}
+
+fn file_size(root: &Path, path: &str) -> Option<u64> {
+    metadata(root.join(path)).ok().filter(|x| x.is_file()).map(|x| x.len())
+}
@@ -0,0 +1,124 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Everything that can make creating or applying a patch fail.
+///
+/// I/O failures are sorted by cause so callers can react to them, e.g. ask the user to close the
+/// game on [`Error::Locked`].
+#[derive(Debug)]
+pub enum Error {
+    /// An argument can't be used, like a directory that doesn't exist
+    InvalidArgument(String),
+    /// `path` is opened by another process
+    Locked { path: PathBuf, source: io::Error },
+    /// Not allowed to read or write `path`
+    PermissionDenied { path: PathBuf, source: io::Error },
+    /// No space left on the volume holding `path`
+    DiskFull { path: PathBuf, source: io::Error },
+    /// The volume holding `path` has `available` bytes free but the operation needs `needed`,
+    /// nothing was written
+    NotEnoughSpace { path: PathBuf, needed: u64, available: u64 },
+    /// `path` was expected to exist
+    NotFound { path: PathBuf, source: io::Error },
+    /// Any other I/O failure on `path`
+    Io { path: PathBuf, source: io::Error },
+    /// A file name isn't valid unicode and can't be stored in a patch
+    NonUnicodePath(PathBuf),
+    /// The patch file is truncated, corrupted or not a patch at all
+    CorruptPatch { reason: String, source: Option<io::Error> },
+    /// The undo journal at `path` has a `line` apply doesn't write, it can't be rolled back
+    CorruptJournal { path: PathBuf, line: String },
+    /// The patch was made by a newer Patchini using a layout this build doesn't know
+    UnsupportedFormat { version: u32, supported: u32 },
+    /// zstd failed to diff `path`
+    Compression { path: String, reason: String },
+    /// These files don't match the ones the patch was made from, nothing was changed
+    SourceMismatch { files: Vec<String> },
+    /// The patch adds these files but they already exist, and
+    /// [`ExistingFiles::Fail`](crate::ExistingFiles::Fail) was chosen
+    FileExists { files: Vec<String> },
+    /// `path` doesn't match the file the patch was made to produce after writing it
+    TargetMismatch { path: String },
+    /// Diffs couldn't be applied to these files, usually because they don't match the version the
+    /// patch was made from. The apply was rolled back.
+    ApplyFailed { files: Vec<String> },
+    /// The operation was stopped through its [`CancelToken`](crate::CancelToken)
+    Cancelled,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Sorts an I/O error that happened on `path` into the matching variant.
+    pub(crate) fn io(path: impl AsRef<Path>, source: io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        match source.kind() {
+            io::ErrorKind::NotFound => Error::NotFound { path, source },
+            io::ErrorKind::PermissionDenied => Error::PermissionDenied { path, source },
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => Error::DiskFull { path, source },
+            _ if is_locked(&source) => Error::Locked { path, source },
+            _ => Error::Io { path, source },
+        }
+    }
+
+    pub(crate) fn corrupt(reason: impl Into<String>) -> Self {
+        Error::CorruptPatch { reason: reason.into(), source: None }
+    }
+
+    /// Builds a [`Error::CorruptPatch`] for an I/O error happening while reading the patch.
+    pub(crate) fn corrupt_io(reason: impl Into<String>, source: io::Error) -> Self {
+        Error::CorruptPatch { reason: reason.into(), source: Some(source) }
+    }
+}
+
+#[cfg(windows)]
+fn is_locked(source: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    matches!(source.raw_os_error(), Some(32 | 33))
+}
+
+#[cfg(not(windows))]
+fn is_locked(source: &io::Error) -> bool {
+    // ETXTBSY, a running executable can't be written
+    source.raw_os_error() == Some(26)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidArgument(reason) => write!(f, "{reason}"),
+            Error::Locked { path, .. } => write!(f, "{} is used by another process", path.display()),
+            Error::PermissionDenied { path, .. } => write!(f, "Permission denied for {}", path.display()),
+            Error::DiskFull { path, .. } => write!(f, "Not enough disk space to write {}", path.display()),
+            Error::NotEnoughSpace { path, needed, available } => write!(f, "Not enough disk space in {}, {needed} bytes are needed but only {available} are free", path.display()),
+            Error::NotFound { path, .. } => write!(f, "{} doesn't exist", path.display()),
+            Error::Io { path, source } => write!(f, "Couldn't access {}: {source}", path.display()),
+            Error::NonUnicodePath(path) => write!(f, "{} isn't a valid unicode path", path.display()),
+            Error::CorruptPatch { reason, source: Some(source) } => write!(f, "Corrupt patch, {reason}: {source}"),
+            Error::CorruptPatch { reason, source: None } => write!(f, "Corrupt patch, {reason}"),
+            Error::CorruptJournal { path, line } => write!(f, "Corrupt undo journal {}, invalid line: {line}", path.display()),
+            Error::UnsupportedFormat { version, supported } => write!(f, "Patch format {version} is too recent, this version of Patchini supports up to {supported}"),
+            Error::Compression { path, reason } => write!(f, "Couldn't diff {path}: {reason}"),
+            Error::SourceMismatch { files } => write!(f, "These files don't match the version the patch was made from: {}", files.join(", ")),
+            Error::FileExists { files } => write!(f, "These files already exist and the patch would replace them: {}", files.join(", ")),
+            Error::TargetMismatch { path } => write!(f, "{path} doesn't match the patched version after writing it"),
+            Error::ApplyFailed { files } => write!(f, "Couldn't apply patch to {}, check logs in backup dir for more info", files.join(", ")),
+            Error::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Locked { source, .. }
+            | Error::PermissionDenied { source, .. }
+            | Error::DiskFull { source, .. }
+            | Error::NotFound { source, .. }
+            | Error::Io { source, .. }
+            | Error::CorruptPatch { source: Some(source), .. } => Some(source),
+            _ => None,
+        }
+    }
+}
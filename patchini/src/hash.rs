@@ -0,0 +1,115 @@
+use crate::error::{Error, Result};
+use crate::layout::checked_path;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Name of the entry listing the files apply expects to find before touching anything.
+pub(crate) const SOURCE_HASHES_NAME: &str = "source_hashes.txt";
+
+/// Name of the entry listing the files apply should end up with.
+pub(crate) const TARGET_HASHES_NAME: &str = "target_hashes.txt";
+
+/// What the patch does with a hashed file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FileKind {
+    Diffed,
+    /// Diffed, with a full copy of the new version to fall back to
+    Hybrid,
+    Removed,
+    Added,
+}
+
+impl FileKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileKind::Diffed => "diff",
+            FileKind::Hybrid => "hybrid",
+            FileKind::Removed => "rm",
+            FileKind::Added => "add",
+        }
+    }
+}
+
+/// One line of a hash list: `<kind> <blake3> <size> <path>`, paths are stored with `/` separators.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct HashEntry {
+    pub(crate) kind: FileKind,
+    pub(crate) hash: String,
+    pub(crate) size: u64,
+    pub(crate) path: String,
+}
+
+/// Size and blake3 hash of a file.
+pub(crate) type Hashed = (u64, String);
+
+pub(crate) fn hash_file(path: impl AsRef<Path>) -> Result<Hashed> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| Error::io(path, e))?;
+    let mut hasher = blake3::Hasher::new();
+    let size = std::io::copy(&mut &file, &mut hasher).map_err(|e| Error::io(path, e))?;
+    Ok((size, hasher.finalize().to_hex().to_string()))
+}
+
+/// Hashes two files in one go, and tells whether they're identical.
+pub(crate) fn hash_pair(old: &Path, new: &Path) -> Result<(Hashed, Hashed, bool)> {
+    let mut old_file = File::open(old).map_err(|e| Error::io(old, e))?;
+    let mut new_file = File::open(new).map_err(|e| Error::io(new, e))?;
+    let (mut old_hasher, mut new_hasher) = (blake3::Hasher::new(), blake3::Hasher::new());
+    let (mut old_buf, mut new_buf) = (vec![0; 1 << 20], vec![0; 1 << 20]);
+    let (mut old_size, mut new_size, mut same) = (0, 0, true);
+    loop {
+        let old_read = fill(&mut old_file, &mut old_buf).map_err(|e| Error::io(old, e))?;
+        let new_read = fill(&mut new_file, &mut new_buf).map_err(|e| Error::io(new, e))?;
+        if old_read == 0 && new_read == 0 { break }
+        old_hasher.update(&old_buf[..old_read]);
+        new_hasher.update(&new_buf[..new_read]);
+        same &= old_buf[..old_read] == new_buf[..new_read];
+        old_size += old_read as u64;
+        new_size += new_read as u64;
+    }
+    Ok(((old_size, old_hasher.finalize().to_hex().to_string()), (new_size, new_hasher.finalize().to_hex().to_string()), same))
+}
+
+/// Reads until `buf` is full or the file ends.
+fn fill(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+pub(crate) fn write_list(entries: &[HashEntry]) -> String {
+    let mut text = String::new();
+    for entry in entries {
+        let path = entry.path.replace(std::path::MAIN_SEPARATOR, "/");
+        let _ = writeln!(text, "{} {} {} {}", entry.kind.as_str(), entry.hash, entry.size, path);
+    }
+    text
+}
+
+pub(crate) fn parse_list(text: &str) -> Result<Vec<HashEntry>> {
+    text.lines().filter(|x| !x.is_empty()).map(|line| {
+        let invalid = || Error::corrupt(format!("invalid hash line {line}"));
+        let mut parts = line.splitn(4, ' ');
+        let kind = match parts.next() {
+            Some("diff") => FileKind::Diffed,
+            Some("hybrid") => FileKind::Hybrid,
+            Some("rm") => FileKind::Removed,
+            Some("add") => FileKind::Added,
+            _ => return Err(invalid()),
+        };
+        let hash = parts.next().filter(|x| x.len() == 64).ok_or_else(invalid)?.to_string();
+        let size = parts.next().and_then(|x| x.parse().ok()).ok_or_else(invalid)?;
+        let path = parts.next().filter(|x| !x.is_empty()).ok_or_else(invalid)?.replace('/', std::path::MAIN_SEPARATOR_STR);
+        checked_path(&path)?;
+        Ok(HashEntry { kind, hash, size, path })
+    }).collect()
+}
@@ -0,0 +1,279 @@
+use crate::error::{Error, Result};
+use crate::hash::{parse_list, FileKind, HashEntry};
+use crate::layout::{listed_paths, DiffOrder, EntryName};
+use crate::manifest::Manifest;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Write};
+use std::fs::File;
+use std::io::Read;
+use tar::{Archive, EntryType};
+use zstd::Decoder;
+
+/// Biggest possible zstd frame header, enough to read the content size.
+const ZSTD_FRAME_HEADER_MAX: usize = 18;
+
+/// A file the patch adds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddedFile {
+    pub path: String,
+    pub size: u64,
+}
+
+/// A file the patch adds without shipping it, apply moves or copies a removed file with the same
+/// content there.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovedFile {
+    /// A removed file with the same content, apply may pick another one if there are several
+    pub from: String,
+    pub path: String,
+    pub size: u64,
+}
+
+/// A file the patch rebuilds from diffs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffedFile {
+    pub path: String,
+    /// Number of chunks with a diff, unchanged chunks are copied from the original
+    pub chunks: u64,
+    /// How many of those are stored whole because that was smaller than their diff
+    pub whole_chunks: u64,
+    /// Compressed size of all the diffs
+    pub delta_size: u64,
+    /// Size of the file before patching, unknown for patches made before hashes were stored
+    pub old_size: Option<u64>,
+    /// Size of the file after patching, unknown for patches made before hashes were stored
+    pub new_size: Option<u64>,
+    /// Size of the full copy apply can fall back to, if the patch has one
+    pub full_size: Option<u64>,
+}
+
+impl DiffedFile {
+    /// Size of the diffs compared to the patched file, `None` when its size is unknown.
+    pub fn ratio(&self) -> Option<f64> {
+        self.new_size.map(|size| if size == 0 { 0.0 } else { self.delta_size as f64 / size as f64 })
+    }
+}
+
+/// Everything a patch does, as returned by [`inspect_patch`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inspection {
+    pub manifest: Manifest,
+    pub removed: Vec<String>,
+    pub added: Vec<AddedFile>,
+    pub moved: Vec<MovedFile>,
+    pub diffed: Vec<DiffedFile>,
+}
+
+impl Display for Inspection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "format version: {}", self.manifest.format_version)?;
+        writeln!(f, "made with: Patchini {}", self.manifest.tool_version)?;
+        writeln!(f, "created: {} (unix time)", self.manifest.created)?;
+        writeln!(f, "chunk size: {}", self.manifest.chunk_size)?;
+        writeln!(f, "compression level: {}", self.manifest.compression_level)?;
+        writeln!(f, "\nremoved files: {}", self.removed.len())?;
+        for path in &self.removed {
+            writeln!(f, "{:>12} {path}", "")?;
+        }
+        writeln!(f, "\nadded files: {}, {} bytes", self.added.len(), self.added.iter().map(|x| x.size).sum::<u64>())?;
+        for file in &self.added {
+            writeln!(f, "{:>12} {}", file.size, file.path)?;
+        }
+        writeln!(f, "\nmoved files: {}, {} bytes", self.moved.len(), self.moved.iter().map(|x| x.size).sum::<u64>())?;
+        for file in &self.moved {
+            writeln!(f, "{:>12} {} -> {}", file.size, file.from, file.path)?;
+        }
+        writeln!(f, "\ndiffed files: {}, {} bytes of diffs", self.diffed.len(), self.diffed.iter().map(|x| x.delta_size).sum::<u64>())?;
+        for file in &self.diffed {
+            write!(f, "{:>12} {} ({} chunk{}", file.delta_size, file.path, file.chunks, if file.chunks == 1 { "" } else { "s" })?;
+            if let (Some(old_size), Some(new_size), Some(ratio)) = (file.old_size, file.new_size, file.ratio()) {
+                write!(f, ", {old_size} -> {new_size} bytes, diff is {:.2}% of the file", ratio * 100.0)?;
+            }
+            if file.whole_chunks > 0 {
+                write!(f, ", {} stored whole", file.whole_chunks)?;
+            }
+            if let Some(full_size) = file.full_size {
+                write!(f, ", full copy of {full_size} bytes")?;
+            }
+            writeln!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl Inspection {
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let manifest = &self.manifest;
+        let _ = write!(json, "{{\"manifest\":{{\"format_version\":{},\"tool_version\":{},\"created\":{},\"chunk_size\":{},\"compression_level\":{},\"long_distance_matching\":{}}}",
+            manifest.format_version, json_string(&manifest.tool_version), manifest.created, manifest.chunk_size, manifest.compression_level, manifest.long_distance_matching);
+        let removed: Vec<String> = self.removed.iter().map(|x| json_string(x)).collect();
+        let _ = write!(json, ",\"removed\":[{}]", removed.join(","));
+        let added: Vec<String> = self.added.iter().map(|x| format!("{{\"path\":{},\"size\":{}}}", json_string(&x.path), x.size)).collect();
+        let _ = write!(json, ",\"added\":[{}]", added.join(","));
+        let moved: Vec<String> = self.moved.iter().map(|x| format!("{{\"from\":{},\"path\":{},\"size\":{}}}", json_string(&x.from), json_string(&x.path), x.size)).collect();
+        let _ = write!(json, ",\"moved\":[{}]", moved.join(","));
+        let diffed: Vec<String> = self.diffed.iter().map(|x| format!("{{\"path\":{},\"chunks\":{},\"whole_chunks\":{},\"delta_size\":{},\"old_size\":{},\"new_size\":{},\"ratio\":{},\"full_size\":{}}}",
+            json_string(&x.path), x.chunks, x.whole_chunks, x.delta_size, json_option(x.old_size), json_option(x.new_size), json_option(x.ratio()), json_option(x.full_size))).collect();
+        let _ = write!(json, ",\"diffed\":[{}]}}", diffed.join(","));
+        json
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(json, "\\u{:04x}", c as u32); }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_option(value: Option<impl Display>) -> String {
+    value.map_or("null".to_string(), |x| x.to_string())
+}
+
+/// Reads `patch` and lists what applying it would do, without touching anything. Fails with
+/// [`Error::UnsupportedFormat`] for patches made by a newer version, [`read_manifest`] still reads
+/// those.
+pub fn inspect_patch(patch: String) -> Result<Inspection> {
+    let patch_file = File::open(&patch).map_err(|e| Error::io(&patch, e))?;
+    let result = Decoder::new(patch_file).map_err(|e| Error::io(&patch, e))?;
+    let mut a = Archive::new(result);
+    let mut manifest = None;
+    let mut inspection = Inspection { manifest: Manifest::legacy(), removed: Vec::new(), added: Vec::new(), moved: Vec::new(), diffed: Vec::new() };
+    let mut sources = HashMap::new();
+    let mut targets = HashMap::new();
+    let mut diff_order = DiffOrder::default();
+    for file in a.entries().map_err(|e| Error::corrupt_io("couldn't list entries", e))? {
+        let mut file = file.map_err(|e| Error::corrupt_io("couldn't read entry", e))?;
+        if file.header().entry_type() == EntryType::Directory {
+            continue
+        }
+        let name = file.path().map_err(|e| Error::corrupt_io("couldn't get entry path", e))?
+            .to_str().ok_or(Error::corrupt("entry path isn't valid unicode"))?.to_string();
+        let entry = EntryName::parse(&name)?;
+        // Like apply, only a first entry counts as the manifest
+        if manifest.is_none() {
+            let found = if entry == EntryName::Manifest { Manifest::parse(&read_text(&mut file, &name)?)? } else { Manifest::legacy() };
+            // A newer layout could make the rest mean something else
+            found.check_supported()?;
+            manifest = Some(found);
+            if entry == EntryName::Manifest {
+                continue
+            }
+        }
+        match entry {
+            EntryName::Manifest => return Err(Error::corrupt("manifest isn't the first entry")),
+            EntryName::SourceHashes => sources = by_path(parse_list(&read_text(&mut file, &name)?)?),
+            EntryName::TargetHashes => targets = by_path(parse_list(&read_text(&mut file, &name)?)?),
+            EntryName::Added(path) => inspection.added.push(AddedFile { path, size: file.size() }),
+            EntryName::Full(path) => {
+                let diffed = inspection.diffed.iter_mut().find(|x| x.path == path).ok_or_else(|| Error::corrupt(format!("full copy of {path} doesn't follow its diffs")))?;
+                diffed.full_size = Some(file.size());
+            }
+            EntryName::Diff { path, chunk, whole } => {
+                if diff_order.next(&path, chunk)? {
+                    inspection.diffed.push(DiffedFile { path, chunks: 1, whole_chunks: whole as u64, delta_size: file.size(), old_size: None, new_size: None, full_size: None });
+                } else if let Some(last) = inspection.diffed.last_mut() {
+                    last.chunks += 1;
+                    last.whole_chunks += whole as u64;
+                    last.delta_size += file.size();
+                }
+            }
+            EntryName::Moved => {
+                for path in listed_paths(&read_text(&mut file, &name)?)? {
+                    let target = targets.get(&path).ok_or_else(|| Error::corrupt(format!("moved file {path} has no hash")))?;
+                    let from = sources.values().filter(|x| x.kind == FileKind::Removed && x.hash == target.hash && x.size == target.size).map(|x| &x.path).min()
+                        .ok_or_else(|| Error::corrupt(format!("no removed file has the content of {path}")))?;
+                    inspection.moved.push(MovedFile { from: from.clone(), path, size: target.size });
+                }
+            }
+            EntryName::Removed => inspection.removed = listed_paths(&read_text(&mut file, &name)?)?,
+        }
+    }
+    for file in &mut inspection.diffed {
+        file.old_size = sources.get(&file.path).map(|x: &HashEntry| x.size);
+        file.new_size = targets.get(&file.path).map(|x: &HashEntry| x.size);
+    }
+    inspection.manifest = manifest.unwrap_or_else(Manifest::legacy);
+    Ok(inspection)
+}
+
+pub(crate) fn read_text(file: &mut impl Read, name: &str) -> Result<String> {
+    let mut text = String::new();
+    file.read_to_string(&mut text).map_err(|e| Error::corrupt_io(format!("couldn't read {name}"), e))?;
+    Ok(text)
+}
+
+fn by_path(entries: Vec<HashEntry>) -> HashMap<String, HashEntry> {
+    entries.into_iter().map(|x| (x.path.clone(), x)).collect()
+}
+
+/// Reads the manifest of `patch`, patches made before manifests existed get
+/// [`format_version`](Manifest::format_version) 0.
+pub fn read_manifest(patch: String) -> Result<Manifest> {
+    let patch_file = File::open(&patch).map_err(|e| Error::io(&patch, e))?;
+    let result = Decoder::new(patch_file).map_err(|e| Error::io(&patch, e))?;
+    let mut a = Archive::new(result);
+    for file in a.entries().map_err(|e| Error::corrupt_io("couldn't list entries", e))? {
+        let mut file = file.map_err(|e| Error::corrupt_io("couldn't read entry", e))?;
+        if file.header().entry_type() == EntryType::Directory {
+            continue
+        }
+        if file.path().map_err(|e| Error::corrupt_io("couldn't get entry path", e))?.as_os_str() != crate::manifest::MANIFEST_NAME {
+            break
+        }
+        return Manifest::parse(&read_text(&mut file, crate::manifest::MANIFEST_NAME)?)
+    }
+    Ok(Manifest::legacy())
+}
+
+/// Checks `patch` can be applied without needing a target: the zstd checksum, the tar structure,
+/// the entry names and the header of every diff. Catches truncated or corrupted downloads before
+/// anything gets touched.
+pub fn verify_patch(patch: String) -> Result<()> {
+    let patch_file = File::open(&patch).map_err(|e| Error::io(&patch, e))?;
+    let result = Decoder::new(patch_file).map_err(|e| Error::io(&patch, e))?;
+    let mut a = Archive::new(result);
+    let mut first = true;
+    let mut diff_order = DiffOrder::default();
+    for file in a.entries().map_err(|e| Error::corrupt_io("couldn't list entries", e))? {
+        let mut file = file.map_err(|e| Error::corrupt_io("couldn't read entry", e))?;
+        if file.header().entry_type() == EntryType::Directory {
+            continue
+        }
+        let name = file.path().map_err(|e| Error::corrupt_io("couldn't get entry path", e))?
+            .to_str().ok_or(Error::corrupt("entry path isn't valid unicode"))?.to_string();
+        match EntryName::parse(&name)? {
+            EntryName::Manifest if first => Manifest::parse(&read_text(&mut file, &name)?)?.check_supported()?,
+            EntryName::Manifest => return Err(Error::corrupt("manifest isn't the first entry")),
+            EntryName::SourceHashes | EntryName::TargetHashes => { parse_list(&read_text(&mut file, &name)?)?; }
+            EntryName::Moved | EntryName::Removed => { listed_paths(&read_text(&mut file, &name)?)?; }
+            EntryName::Added(_) | EntryName::Full(_) => {}
+            EntryName::Diff { path, chunk, .. } => {
+                diff_order.next(&path, chunk)?;
+                let mut header = Vec::with_capacity(ZSTD_FRAME_HEADER_MAX);
+                Read::by_ref(&mut file).take(ZSTD_FRAME_HEADER_MAX as u64).read_to_end(&mut header).map_err(|e| Error::corrupt_io(format!("couldn't read {name}"), e))?;
+                if !matches!(zstd_safe::get_frame_content_size(&header), Ok(Some(_))) {
+                    return Err(Error::corrupt(format!("{name} isn't a valid diff")));
+                }
+            }
+        }
+        // Whatever wasn't read yet still goes through the decompression, and its checksum
+        std::io::copy(&mut file, &mut std::io::sink()).map_err(|e| Error::corrupt_io(format!("couldn't read {name}"), e))?;
+        first = false;
+    }
+    // The checksum is at the end of the zstd frame, after the tar end marker
+    std::io::copy(&mut a.into_inner(), &mut std::io::sink()).map_err(|e| Error::corrupt_io("couldn't read the end of the patch", e))?;
+    Ok(())
+}
@@ -0,0 +1,277 @@
+use crate::error::{Error, Result};
+use crate::layout::checked_path;
+use crate::progress::{Event, Phase};
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the undo journal of the last apply, left in the backup directory.
+pub(crate) const JOURNAL_NAME: &str = "journal.txt";
+
+/// Name of the journal of an apply in progress. It only replaces [`JOURNAL_NAME`] once the apply
+/// finishes, so one that fails leaves the previous apply able to be rolled back.
+const NEW_JOURNAL_NAME: &str = "journal.txt.new";
+
+/// Directory in the backup dir holding the previous apply's backups an apply in progress needs the
+/// place of, deleted once it finishes.
+pub(crate) const PREVIOUS_DIR: &str = "previous";
+
+/// Last line of the journal of an apply that went through, anything else was interrupted.
+const FINISHED: &str = "finished";
+
+/// A change made by apply to the target directory, paths are relative to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Undo {
+    /// `path` was moved to `backup`
+    Moved { path: String, backup: PathBuf },
+    /// `path` didn't exist before
+    Created(String),
+    /// The directory `path` didn't exist before
+    CreatedDir(PathBuf),
+    /// The previous apply's backup `path` was moved to `aside`, which is gone once the apply finished
+    SetAside { path: PathBuf, aside: PathBuf },
+}
+
+impl Undo {
+    fn to_line(&self) -> String {
+        match self {
+            Undo::Moved { path, backup } => format!("moved\t{path}\t{}", backup.display()),
+            Undo::Created(path) => format!("created\t{path}"),
+            Undo::CreatedDir(path) => format!("dir\t{}", path.display()),
+            Undo::SetAside { path, aside } => format!("aside\t{}\t{}", path.display(), aside.display()),
+        }
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split('\t');
+        // Rolling back renames and deletes these, they can't lead outside the directory either
+        let checked = |path| checked_path(path).ok();
+        let change = match (parts.next(), parts.next(), parts.next()) {
+            (Some("moved"), Some(path), Some(backup)) => Undo::Moved { path: checked(path)?.to_string(), backup: checked(backup)?.into() },
+            (Some("created"), Some(path), None) => Undo::Created(checked(path)?.to_string()),
+            (Some("dir"), Some(path), None) => Undo::CreatedDir(checked(path)?.into()),
+            (Some("aside"), Some(path), Some(aside)) => Undo::SetAside { path: checked(path)?.into(), aside: checked(aside)?.into() },
+            _ => return None,
+        };
+        if parts.next().is_some() { return None }
+        Some(change)
+    }
+
+    /// Puts back what the change replaced. Reverting a change that was already reverted, or that
+    /// was recorded but never made, does nothing.
+    fn revert(&self, root: &Path, report: &mut impl FnMut(&Event)) -> Result<()> {
+        match self {
+            Undo::Created(path) => {
+                let full_path = root.join(path);
+                match fs::remove_file(&full_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io(full_path, e)),
+                    _ => Ok(()),
+                }
+            }
+            Undo::Moved { path, backup } => {
+                let (full_path, backup) = (root.join(path), root.join(backup));
+                report(&Event::FileStarted { phase: Phase::RollingBack, path, size: fs::metadata(&backup).map_or(0, |m| m.len()) });
+                if !backup.exists() && full_path.exists() {
+                    Ok(())
+                } else {
+                    fs::rename(&backup, &full_path).map_err(|e| Error::io(full_path, e))
+                }
+            }
+            Undo::CreatedDir(path) => {
+                let _ = fs::remove_dir(root.join(path));
+                Ok(())
+            }
+            Undo::SetAside { path, aside } => {
+                let (full_path, aside) = (root.join(path), root.join(aside));
+                match fs::rename(&aside, &full_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io(full_path, e)),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Every change made by an apply, written to disk as it happens so it can be undone later even if
+/// the process doesn't get to finish.
+///
+/// Every change is recorded before it's made, so a partially written file is removed too and a
+/// file moved to the backup dir is always found again. Rolling back skips moves that didn't happen.
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: Option<File>,
+    changes: Vec<Undo>,
+    /// Where the line of each change starts in the file
+    starts: Vec<u64>,
+    /// Where the line of the last change ends
+    len: u64,
+}
+
+impl Journal {
+    /// A journal for a new apply in `backup_dir`. The file is only created on the first change, so
+    /// an apply failing its checks leaves no trace.
+    pub(crate) fn new(backup_dir: &Path) -> Self {
+        Self { path: backup_dir.join(NEW_JOURNAL_NAME), file: None, changes: Vec::new(), starts: Vec::new(), len: 0 }
+    }
+
+    pub(crate) fn push(&mut self, change: Undo) -> Result<()> {
+        let start = self.len;
+        self.len += self.write_line(&change.to_line())?;
+        self.changes.push(change);
+        self.starts.push(start);
+        Ok(())
+    }
+
+    /// Marks the apply as complete and makes its journal the one [`rollback_patch`](crate::rollback_patch)
+    /// uses, replacing the previous apply's. Its backups that were set aside are deleted.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        if self.file.is_some() {
+            self.write_line(FINISHED)?;
+            self.file = None;
+            promote(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `line` and waits for it to reach the disk, returns how many bytes it took.
+    fn write_line(&mut self, line: &str) -> Result<u64> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(File::create(&self.path).map_err(|e| Error::io(&self.path, e))?),
+        };
+        let line = format!("{line}\n");
+        file.write_all(line.as_bytes()).and_then(|_| file.sync_data()).map_err(|e| Error::io(&self.path, e))?;
+        Ok(line.len() as u64)
+    }
+
+    pub(crate) fn extend(&mut self, changes: impl IntoIterator<Item = Undo>) -> Result<()> {
+        changes.into_iter().try_for_each(|x| self.push(x))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Reverts every change in `root` from the last to the first, then deletes the journal.
+    ///
+    /// Each change is cut from the journal once reverted, so a rollback that stopped halfway,
+    /// whether it failed or the process died, picks up where it left off when run again. Stops at
+    /// the first change it can't revert, the ones before may depend on it.
+    pub(crate) fn rollback(mut self, root: &Path, report: &mut impl FnMut(&Event)) -> Result<()> {
+        let Some(file) = self.file.take() else { return Ok(()) };
+        let truncate = |len| file.set_len(len).and_then(|_| file.sync_data()).map_err(|e| Error::io(&self.path, e));
+        // Neither finished nor torn anymore once something was reverted
+        truncate(self.len)?;
+        while let (Some(change), Some(start)) = (self.changes.last(), self.starts.last()) {
+            change.revert(root, report)?;
+            truncate(*start)?;
+            self.changes.pop();
+            self.starts.pop();
+        }
+        drop(file);
+        fs::remove_file(&self.path).map_err(|e| Error::io(&self.path, e))
+    }
+}
+
+/// Makes the finished journal at `path` the last apply's and deletes the backups it set aside.
+fn promote(path: &Path) -> Result<()> {
+    let journal = path.with_file_name(JOURNAL_NAME);
+    if path != journal {
+        fs::rename(path, &journal).map_err(|e| Error::io(&journal, e))?;
+    }
+    // Nothing refers to them anymore, what's left is only wasted space
+    let _ = fs::remove_dir_all(path.with_file_name(PREVIOUS_DIR));
+    Ok(())
+}
+
+/// Reads the journal at `path`, and whether its apply finished.
+pub(crate) fn load(path: &Path) -> Result<(Journal, bool)> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(path).map_err(|e| Error::io(path, e))?;
+    let mut text = String::new();
+    std::io::Read::read_to_string(&mut file, &mut text).map_err(|e| Error::io(path, e))?;
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for line in text.split_inclusive('\n') {
+        if !line.trim_end_matches('\n').is_empty() {
+            lines.push((start, line));
+        }
+        start += line.len() as u64;
+    }
+    // A last line without its newline was cut off by a crash before the change it records was made
+    if lines.last().is_some_and(|(_, x)| !x.ends_with('\n') && *x != FINISHED) {
+        lines.pop();
+    }
+    let finished = lines.last().is_some_and(|(_, x)| x.trim_end_matches('\n') == FINISHED);
+    if finished {
+        lines.pop();
+    }
+    let mut journal = Journal { path: path.to_path_buf(), file: Some(file), changes: Vec::new(), starts: Vec::new(), len: 0 };
+    for (start, line) in lines {
+        let line = line.trim_end_matches('\n');
+        let change = Undo::parse(line).ok_or_else(|| Error::CorruptJournal { path: path.to_path_buf(), line: line.to_string() })?;
+        journal.changes.push(change);
+        journal.starts.push(start);
+        journal.len = start + line.len() as u64 + 1;
+    }
+    Ok((journal, finished))
+}
+
+/// The journal of an apply that died in `backup_dir` before finishing, to be rolled back. One that
+/// died after finishing but before replacing the previous journal does so now.
+pub(crate) fn interrupted(backup_dir: &Path) -> Result<Option<Journal>> {
+    // Older versions wrote the journal in place
+    for name in [NEW_JOURNAL_NAME, JOURNAL_NAME] {
+        let path = backup_dir.join(name);
+        if !path.is_file() {
+            continue
+        }
+        let (journal, finished) = load(&path)?;
+        if !finished {
+            return Ok(Some(journal));
+        }
+        drop(journal);
+        promote(&path)?;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `text` as a journal and loads it back.
+    fn load_text(name: &str, text: &str) -> Result<(Vec<Undo>, bool)> {
+        let path = std::env::temp_dir().join(format!("patchini-journal-{name}-{}.txt", std::process::id()));
+        fs::write(&path, text).unwrap();
+        let result = load(&path).map(|(journal, finished)| (journal.changes.clone(), finished));
+        let _ = fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn invalid_lines_are_corrupt() {
+        for text in ["copied\ta.txt\n", "created\ta.txt\textra\n", "moved\ta.txt\n", "created\t../a.txt\n", "dir\t/abs\n"] {
+            let result = load_text("invalid", text);
+            assert!(matches!(&result, Err(Error::CorruptJournal { line, .. }) if format!("{line}\n") == text), "{text:?}: {result:?}");
+        }
+    }
+
+    #[test]
+    fn torn_last_line_is_dropped() {
+        let created = |path: &str| Undo::Created(path.to_string());
+        assert_eq!(load_text("whole", "created\ta.txt\ncreated\tb.txt\n").unwrap(), (vec![created("a.txt"), created("b.txt")], false));
+        assert_eq!(load_text("torn", "created\ta.txt\ncreated\tb.t").unwrap(), (vec![created("a.txt")], false));
+        // Torn in the middle of its first field, it doesn't even parse
+        assert_eq!(load_text("torn_kind", "created\ta.txt\nmov").unwrap(), (vec![created("a.txt")], false));
+        assert_eq!(load_text("finished", "created\ta.txt\nfinished\n").unwrap(), (vec![created("a.txt")], true));
+        assert_eq!(load_text("finished_torn", "created\ta.txt\nfinished").unwrap(), (vec![created("a.txt")], true));
+        assert_eq!(load_text("empty", "").unwrap(), (vec![], false));
+    }
+
+    #[test]
+    fn only_the_last_line_can_be_torn() {
+        let result = load_text("torn_middle", "created\ta.txt\nmov\ncreated\tb.txt\n");
+        assert!(matches!(&result, Err(Error::CorruptJournal { line, .. }) if line == "mov"), "{result:?}");
+    }
+}
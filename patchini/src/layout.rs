@@ -0,0 +1,143 @@
+use crate::error::{Error, Result};
+use crate::hash::{SOURCE_HASHES_NAME, TARGET_HASHES_NAME};
+use crate::manifest::MANIFEST_NAME;
+use std::collections::HashSet;
+use std::path::{Component, Path};
+
+/// Extension of diff entries, followed by the 1-based chunk number.
+pub(crate) const DIFF_EXT: &str = ".zspatch";
+
+/// Extension of chunks stored whole because that's smaller than their diff, followed by the
+/// 1-based chunk number. They're plain zstd frames that don't need the original.
+pub(crate) const WHOLE_EXT: &str = ".zswhole";
+
+/// What an archive entry holds, going by its name. Paths use the native separator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum EntryName {
+    Manifest,
+    SourceHashes,
+    TargetHashes,
+    /// `new_files/<path>`
+    Added(String),
+    /// `diff_files/<path>.zspatchNNN`, or `diff_files/<path>.zswholeNNN` when `whole`
+    Diff { path: String, chunk: u64, whole: bool },
+    /// `full_files/<path>`, the whole patched version of a diffed file, after its diffs
+    Full(String),
+    /// `moved_files.txt`, added files apply makes from a removed file with the same content
+    Moved,
+    /// `rm_files.txt`
+    Removed,
+}
+
+impl EntryName {
+    pub(crate) fn parse(name: &str) -> Result<Self> {
+        let name = name.replace('/', std::path::MAIN_SEPARATOR_STR);
+        let (dir, path) = match name.split_once(std::path::MAIN_SEPARATOR) {
+            Some((dir, path)) => (dir, Some(path)),
+            None => (name.as_str(), None),
+        };
+        match (dir, path) {
+            (MANIFEST_NAME, None) => Ok(EntryName::Manifest),
+            (SOURCE_HASHES_NAME, None) => Ok(EntryName::SourceHashes),
+            (TARGET_HASHES_NAME, None) => Ok(EntryName::TargetHashes),
+            ("moved_files.txt", None) => Ok(EntryName::Moved),
+            ("rm_files.txt", None) => Ok(EntryName::Removed),
+            ("new_files", Some(path)) => Ok(EntryName::Added(checked_path(path)?.to_string())),
+            ("full_files", Some(path)) => Ok(EntryName::Full(checked_path(path)?.to_string())),
+            ("diff_files", Some(path)) => {
+                // Whichever extension comes last, the path itself may contain the other one
+                let (ext_pos, ext) = [DIFF_EXT, WHOLE_EXT].into_iter().filter_map(|ext| path.rfind(ext).map(|pos| (pos, ext))).max()
+                    .ok_or_else(|| Error::corrupt(format!("file {path} doesn't contain extension")))?;
+                let chunk = path[ext_pos + ext.len()..].parse::<u64>().map_err(|_| Error::corrupt(format!("couldn't parse {ext} number for {path}")))?;
+                if ext_pos == 0 || chunk == 0 {
+                    return Err(Error::corrupt(format!("invalid diff entry {path}")));
+                }
+                Ok(EntryName::Diff { path: checked_path(&path[..ext_pos])?.to_string(), chunk, whole: ext == WHOLE_EXT })
+            }
+            _ => Err(Error::corrupt(format!("unknown file in patch: {}", name.replace(std::path::MAIN_SEPARATOR, "/")))),
+        }
+    }
+}
+
+/// Checks a path read from a patch stays inside the directory it's applied to, so it has to be
+/// relative and made of plain names only, no `..`, `.` or drive prefix.
+pub(crate) fn checked_path(path: &str) -> Result<&str> {
+    if path.is_empty() || !Path::new(path).components().all(|x| matches!(x, Component::Normal(_))) {
+        return Err(Error::corrupt(format!("{path} isn't a path inside the target directory")));
+    }
+    Ok(path)
+}
+
+/// Paths listed in `rm_files.txt` or `moved_files.txt`, one per line. They're stored with `/` like
+/// in the hash lists, and returned with the native separator.
+pub(crate) fn listed_paths(text: &str) -> Result<Vec<String>> {
+    text.lines().filter(|x| !x.is_empty()).map(|x| {
+        let path = x.replace('/', std::path::MAIN_SEPARATOR_STR);
+        checked_path(&path)?;
+        Ok(path)
+    }).collect()
+}
+
+/// Writes `paths` for [`listed_paths`] to read back on any platform.
+pub(crate) fn write_paths(paths: impl IntoIterator<Item = impl AsRef<str>>) -> String {
+    paths.into_iter().map(|x| format!("{}\n", x.as_ref().replace(std::path::MAIN_SEPARATOR, "/"))).collect()
+}
+
+/// Checks diff entries come file by file with increasing chunk numbers, apply relies on it.
+#[derive(Default)]
+pub(crate) struct DiffOrder {
+    current: Option<(String, u64)>,
+    done: HashSet<String>,
+}
+
+impl DiffOrder {
+    /// Returns whether `path` starts a new file.
+    pub(crate) fn next(&mut self, path: &str, chunk: u64) -> Result<bool> {
+        match &mut self.current {
+            Some((current, last)) if current == path => {
+                if chunk <= *last {
+                    return Err(Error::corrupt(format!("diffs of {path} aren't in order")));
+                }
+                *last = chunk;
+                Ok(false)
+            }
+            _ => {
+                if let Some((previous, _)) = self.current.take() {
+                    self.done.insert(previous);
+                }
+                if self.done.contains(path) {
+                    return Err(Error::corrupt(format!("diffs of {path} are split up")));
+                }
+                self.current = Some((path.to_string(), chunk));
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listed_paths_use_the_native_separator() {
+        let native = Path::new("x").join("rm.bin").to_str().unwrap().to_string();
+        assert_eq!(listed_paths("x/rm.bin\n\ntop.bin\n").unwrap(), [native.clone(), "top.bin".to_string()]);
+        assert_eq!(write_paths([&native]), "x/rm.bin\n");
+        assert!(listed_paths("x/../rm.bin\n").is_err());
+    }
+    #[test]
+    fn diff_order() {
+        let mut order = DiffOrder::default();
+        assert!(order.next("a", 0).unwrap());
+        assert!(!order.next("a", 1).unwrap());
+        assert!(!order.next("a", 3).unwrap());
+        assert!(order.next("b", 0).unwrap());
+        assert!(matches!(order.next("b", 0), Err(Error::CorruptPatch { .. })));
+        assert!(matches!(order.next("a", 4), Err(Error::CorruptPatch { .. })));
+
+        let mut order = DiffOrder::default();
+        order.next("a", 2).unwrap();
+        assert!(matches!(order.next("a", 1), Err(Error::CorruptPatch { .. })));
+    }
+}
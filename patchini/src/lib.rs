@@ -0,0 +1,26 @@
+//! Patch engine behind Patchini: diffs two directory trees into a `.patchini` file and applies it
+//! back onto an install.
+//!
+//! Nothing in here depends on a GUI, frontends only have to listen to [`Event`]s.
+
+mod cancel;
+mod dry_run;
+mod error;
+mod hash;
+mod inspect;
+mod journal;
+mod layout;
+mod manifest;
+mod options;
+mod patch;
+mod progress;
+mod space;
+
+pub use cancel::CancelToken;
+pub use dry_run::{dry_run_patch, dry_run_patch_from, Action, DryRun, Mismatch, PlannedChange};
+pub use error::{Error, Result};
+pub use manifest::{Manifest, FORMAT_VERSION};
+pub use options::{ApplyOptions, ConflictPolicy, ConflictRule, CreateOptions, ExistingFiles};
+pub use inspect::{inspect_patch, read_manifest, verify_patch, AddedFile, DiffedFile, Inspection, MovedFile};
+pub use patch::{apply_patch, apply_patch_from, create_patch, create_patch_to, rollback_patch};
+pub use progress::{Event, Phase, Progress};
@@ -0,0 +1,134 @@
+use crate::error::{Error, Result};
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the .patchini layout written by this build. Bump it whenever an older apply would
+/// misread a newer patch.
+pub const FORMAT_VERSION: u32 = 6;
+
+/// Name of the manifest entry, always the first one in the archive.
+pub(crate) const MANIFEST_NAME: &str = "manifest.txt";
+
+/// Size of the chunks files are diffed in, zstd can't use bigger prefixes.
+pub(crate) const CHUNK_SIZE: usize = 0x77777777;
+
+/// Describes how a patch was made, stored as `key=value` lines in `manifest.txt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// Layout version, see [`FORMAT_VERSION`]. 0 for patches made before manifests existed.
+    pub format_version: u32,
+    /// Version of Patchini that made the patch
+    pub tool_version: String,
+    /// Creation time, in seconds since the Unix epoch
+    pub created: u64,
+    /// Size of the chunks files were diffed in
+    pub chunk_size: u64,
+    /// zstd level used for the diffs
+    pub compression_level: i32,
+    /// Whether long distance matching was enabled for the diffs
+    pub long_distance_matching: bool,
+}
+
+impl Manifest {
+    pub(crate) fn new(compression_level: i32) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs()),
+            chunk_size: CHUNK_SIZE as u64,
+            compression_level,
+            long_distance_matching: true,
+        }
+    }
+
+    /// What patches without a manifest were made with.
+    pub(crate) fn legacy() -> Self {
+        Self {
+            format_version: 0,
+            tool_version: "unknown".to_string(),
+            created: 0,
+            chunk_size: CHUNK_SIZE as u64,
+            compression_level: 0,
+            long_distance_matching: true,
+        }
+    }
+
+    pub(crate) fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "format_version={}", self.format_version);
+        let _ = writeln!(text, "tool_version={}", self.tool_version);
+        let _ = writeln!(text, "created={}", self.created);
+        let _ = writeln!(text, "chunk_size={}", self.chunk_size);
+        let _ = writeln!(text, "compression_level={}", self.compression_level);
+        let _ = writeln!(text, "long_distance_matching={}", self.long_distance_matching);
+        text
+    }
+
+    /// Reads a manifest, unknown keys are ignored so newer patches of the same format still load.
+    pub(crate) fn parse(text: &str) -> Result<Self> {
+        let mut manifest = Self::legacy();
+        let mut has_version = false;
+        for line in text.lines().filter(|x| !x.trim().is_empty()) {
+            let (key, value) = line.split_once('=').ok_or_else(|| Error::corrupt(format!("invalid manifest line {line}")))?;
+            let invalid = || Error::corrupt(format!("invalid manifest value for {key}: {value}"));
+            match key {
+                "format_version" => {
+                    manifest.format_version = value.parse().map_err(|_| invalid())?;
+                    has_version = true;
+                }
+                "tool_version" => manifest.tool_version = value.to_string(),
+                "created" => manifest.created = value.parse().map_err(|_| invalid())?,
+                "chunk_size" => manifest.chunk_size = value.parse().map_err(|_| invalid())?,
+                "compression_level" => manifest.compression_level = value.parse().map_err(|_| invalid())?,
+                "long_distance_matching" => manifest.long_distance_matching = value.parse().map_err(|_| invalid())?,
+                _ => {}
+            }
+        }
+        if !has_version {
+            return Err(Error::corrupt("manifest has no format version"));
+        }
+        if manifest.chunk_size == 0 {
+            return Err(Error::corrupt("manifest chunk size is 0"));
+        }
+        Ok(manifest)
+    }
+
+    /// Fails if this build doesn't know how to apply the patch.
+    pub(crate) fn check_supported(&self) -> Result<()> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(Error::UnsupportedFormat { version: self.format_version, supported: FORMAT_VERSION });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let manifest = Manifest::new(19);
+        assert_eq!(Manifest::parse(&manifest.to_text()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn missing_keys_are_legacy_and_unknown_ones_ignored() {
+        let manifest = Manifest::parse("format_version=2\n\nsigned_by=someone\n").unwrap();
+        assert_eq!(manifest, Manifest { format_version: 2, ..Manifest::legacy() });
+    }
+
+    #[test]
+    fn invalid_manifests_are_corrupt() {
+        for text in ["", "tool_version=1.0\n", "format_version\n", "format_version=new\n", "format_version=1\nchunk_size=0\n", "format_version=1\nlong_distance_matching=yes\n"] {
+            assert!(matches!(Manifest::parse(text), Err(Error::CorruptPatch { .. })), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn newer_formats_are_unsupported() {
+        assert!(Manifest { format_version: FORMAT_VERSION, ..Manifest::legacy() }.check_supported().is_ok());
+        let result = Manifest { format_version: FORMAT_VERSION + 1, ..Manifest::legacy() }.check_supported();
+        assert!(matches!(result, Err(Error::UnsupportedFormat { version, supported: FORMAT_VERSION }) if version == FORMAT_VERSION + 1));
+    }
+}
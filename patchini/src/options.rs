@@ -0,0 +1,224 @@
+use crate::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// What apply does when a file the patch adds already exists in the target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingFiles {
+    /// Move it to the backup dir and write the added file, a rollback puts it back
+    #[default]
+    Overwrite,
+    /// Keep it and leave out the added file
+    Skip,
+    /// Refuse to apply the patch, with [`Error::FileExists`]
+    Fail,
+}
+
+impl Display for ExistingFiles {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            ExistingFiles::Overwrite => "overwrite",
+            ExistingFiles::Skip => "skip",
+            ExistingFiles::Fail => "fail",
+        })
+    }
+}
+
+impl FromStr for ExistingFiles {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(ExistingFiles::Overwrite),
+            "skip" => Ok(ExistingFiles::Skip),
+            "fail" => Ok(ExistingFiles::Fail),
+            _ => Err(Error::InvalidArgument(format!("Unknown policy {s}, expected overwrite, skip or fail"))),
+        }
+    }
+}
+
+/// Choices for [`apply_patch`](crate::apply_patch), the defaults suit most installs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApplyOptions {
+    pub existing_files: ExistingFiles,
+    /// What to do with files that don't match the version the patch was made from
+    pub conflicts: ConflictPolicy,
+    /// Overrides `conflicts` for some files, the first rule matching a file is used
+    pub conflict_rules: Vec<ConflictRule>,
+}
+
+impl ApplyOptions {
+    /// Policy for conflicts on `path`.
+    pub fn conflict_policy(&self, path: &str) -> ConflictPolicy {
+        self.conflict_rules.iter().find(|x| x.matches(path)).map_or(self.conflicts, |x| x.policy)
+    }
+}
+
+/// What apply does with a file that doesn't match the version the patch was made from, because it
+/// was modified locally or is missing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Refuse to apply the patch, with [`Error::SourceMismatch`] or [`Error::ApplyFailed`], even if
+    /// it carries a full copy
+    Fail,
+    /// Leave the file as it is
+    Skip,
+    /// Replace the file with the full copy the patch carries, failing like [`ConflictPolicy::Fail`]
+    /// if it has none. Files the patch removes have none.
+    #[default]
+    Overwrite,
+    /// Leave the file as it is and list it in `backup/conflicts.txt`
+    Keep,
+}
+
+impl ConflictPolicy {
+    /// The policy apply follows for a conflicting file, [`ConflictPolicy::Fail`] when it asks to
+    /// overwrite a file without a full copy. Files the patch removes never have one.
+    pub(crate) fn for_file(self, removed: bool, full_copy: bool) -> ConflictPolicy {
+        match self {
+            ConflictPolicy::Overwrite if removed || !full_copy => ConflictPolicy::Fail,
+            policy => policy,
+        }
+    }
+}
+
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            ConflictPolicy::Fail => "fail",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Keep => "keep",
+        })
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ConflictPolicy::Fail),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep" => Ok(ConflictPolicy::Keep),
+            _ => Err(Error::InvalidArgument(format!("Unknown policy {s}, expected fail, skip, overwrite or keep"))),
+        }
+    }
+}
+
+/// Uses `policy` for the files matching `glob`, written `glob=policy` as text.
+///
+/// In the glob `*` stands for any part of a name, `**` for any number of directories and `?` for
+/// one character, directories are separated by `/`. A glob without `/` matches names in any
+/// directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConflictRule {
+    pub glob: String,
+    pub policy: ConflictPolicy,
+}
+
+impl ConflictRule {
+    pub fn matches(&self, path: &str) -> bool {
+        glob_matches(&self.glob, path)
+    }
+}
+
+impl FromStr for ConflictRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (glob, policy) = s.rsplit_once('=').filter(|(glob, _)| !glob.is_empty())
+            .ok_or_else(|| Error::InvalidArgument(format!("Invalid conflict rule {s}, expected glob=policy")))?;
+        Ok(ConflictRule { glob: glob.to_string(), policy: policy.parse()? })
+    }
+}
+
+/// Matches `path` against `glob`, see [`ConflictRule`] for the syntax.
+pub(crate) fn glob_matches(glob: &str, path: &str) -> bool {
+    let path = path.replace(std::path::MAIN_SEPARATOR, "/");
+    let path: Vec<&str> = path.split('/').collect();
+    if glob.contains('/') {
+        let glob: Vec<&str> = glob.split('/').collect();
+        match_dirs(&glob, &path)
+    } else {
+        path.last().is_some_and(|name| match_name(&glob.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>()))
+    }
+}
+
+fn match_dirs(glob: &[&str], path: &[&str]) -> bool {
+    match glob.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| match_dirs(rest, &path[i..])),
+        Some((first, rest)) => path.split_first().is_some_and(|(name, path)| {
+            match_name(&first.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>()) && match_dirs(rest, path)
+        }),
+    }
+}
+
+fn match_name(glob: &[char], name: &[char]) -> bool {
+    match glob.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| match_name(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && match_name(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && match_name(rest, &name[1..]),
+    }
+}
+
+/// Choices for [`create_patch`](crate::create_patch).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreateOptions {
+    /// zstd level of the diffs, negative values trade ratio for speed
+    pub level: i32,
+    /// Diffed files up to this size also get a full copy in the patch, which apply falls back to
+    /// when the file doesn't match the version the patch was made from
+    pub full_copy_max_size: Option<u64>,
+    /// Diffed files matching one of these globs get a full copy whatever their size, see
+    /// [`ConflictRule`] for the syntax
+    pub full_copy_globs: Vec<String>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self { level: 3, full_copy_max_size: None, full_copy_globs: Vec::new() }
+    }
+}
+
+impl CreateOptions {
+    pub(crate) fn wants_full_copy(&self, path: &str, size: u64) -> bool {
+        self.full_copy_max_size.is_some_and(|x| size <= x) || self.full_copy_globs.iter().any(|x| glob_matches(x, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_without_slash_matches_file_name() {
+        assert!(glob_matches("*.txt", "a.txt"));
+        assert!(glob_matches("*.txt", "sub/dir/a.txt"));
+        assert!(glob_matches("?.txt", "a.txt"));
+        assert!(!glob_matches("?.txt", "ab.txt"));
+        assert!(!glob_matches("*.txt", "a.txt.bak"));
+        assert!(!glob_matches("sub", "sub/a.txt"));
+    }
+
+    #[test]
+    fn glob_with_slash_matches_whole_path() {
+        assert!(glob_matches("sub/*.txt", "sub/a.txt"));
+        assert!(!glob_matches("sub/*.txt", "other/sub/a.txt"));
+        assert!(!glob_matches("sub/*.txt", "sub/dir/a.txt"));
+        assert!(glob_matches("sub/**/*.txt", "sub/a.txt"));
+        assert!(glob_matches("sub/**/*.txt", "sub/dir/deeper/a.txt"));
+        assert!(glob_matches("**/a.txt", "a.txt"));
+        assert!(glob_matches("**", "sub/a.txt"));
+    }
+
+    #[test]
+    fn glob_matches_native_separators() {
+        let path = std::path::Path::new("sub").join("dir").join("a.txt");
+        assert!(glob_matches("sub/**/a.txt", path.to_str().unwrap()));
+        assert!(glob_matches("*.txt", path.to_str().unwrap()));
+    }
+}
@@ -0,0 +1,874 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{metadata, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::cancel::CancelToken;
+use crate::error::{Error, Result};
+use crate::layout::{listed_paths, write_paths, DiffOrder, EntryName, DIFF_EXT, WHOLE_EXT};
+use crate::journal::{interrupted, load, Journal, Undo, JOURNAL_NAME, PREVIOUS_DIR};
+use crate::hash::{hash_file, hash_pair, parse_list, write_list, FileKind, HashEntry, SOURCE_HASHES_NAME, TARGET_HASHES_NAME};
+use crate::manifest::{Manifest, CHUNK_SIZE, MANIFEST_NAME};
+use crate::options::{ApplyOptions, ConflictPolicy, CreateOptions, ExistingFiles};
+use crate::progress::{CountingReader, Event, Phase, Progress};
+use crate::space::check_space;
+use tar::{Archive, Builder, Entry, EntryType, Header};
+use walkdir::WalkDir;
+use zstd::zstd_safe::{CParameter};
+
+/// Directory apply keeps its backups, log and journal in, relative to the target.
+const BACKUP_DIR: &str = "backup";
+
+/// Paths compare by component, which keeps the order entries had when patches were packed from a
+/// directory tree.
+fn sorted<'a>(files: impl Iterator<Item = &'a String>) -> Vec<&'a String> {
+    let mut files: Vec<&String> = files.collect();
+    files.sort_by(|a, b| Path::new(a).cmp(Path::new(b)));
+    files
+}
+
+fn check_dirs(old_file: &str, new_file: &str) -> Result<()> {
+    if !metadata(old_file).is_ok_and(|x| x.is_dir()) { return Err(Error::InvalidArgument("Old path doesn't exist or is not a directory".to_string())) };
+    if !metadata(new_file).is_ok_and(|x| x.is_dir()) { return Err(Error::InvalidArgument("New path doesn't exist or is not a directory".to_string())) };
+    Ok(())
+}
+
+/// Diffs `old_file` against `new_file` and writes the result to the file `output`, which is removed
+/// again if anything fails. Every step is reported to `progress`, and `cancel` is checked between
+/// files and chunks.
+///
+/// Diffed files picked by `options` also get a full copy, making the patch bigger but letting
+/// apply replace those files when they were modified locally. Added files with the same content
+/// as a removed one aren't stored at all, apply moves or copies the removed file instead.
+///
+/// Fails with [`Error::NotEnoughSpace`] before packing anything if the volume of `output` can't
+/// hold the added files and full copies. Diffs are usually small next to them and can't be sized
+/// beforehand.
+pub fn create_patch(old_file: String, new_file: String, options: &CreateOptions, output: String, progress: &mut dyn Progress, cancel: &CancelToken) -> Result<()> {
+    check_dirs(&old_file, &new_file)?;
+    let file = File::create(&output).map_err(|e| Error::io(&output, e))?;
+    let result = write_patch(old_file, new_file, options, file, Path::new(&output), true, progress, cancel);
+    // A partial patch is worse than none
+    if result.is_err() {
+        let _ = fs::remove_file(&output);
+    }
+    result.map(|_| ())
+}
+
+/// Same as [`create_patch`], but writes the patch to any sink and hands it back once finished.
+/// Failures to write to it are reported for the path `<output>`.
+pub fn create_patch_to<W: Write>(old_file: String, new_file: String, options: &CreateOptions, output: W, progress: &mut dyn Progress, cancel: &CancelToken) -> Result<W> {
+    write_patch(old_file, new_file, options, output, Path::new("<output>"), false, progress, cancel)
+}
+
+/// `check_output_space` checks the volume of `output_name` can hold the added files and full copies.
+#[allow(clippy::too_many_arguments)]
+fn write_patch<W: Write>(old_file: String, new_file: String, options: &CreateOptions, output: W, output_name: &Path, check_output_space: bool, progress: &mut dyn Progress, cancel: &CancelToken) -> Result<W> {
+    check_dirs(&old_file, &new_file)?;
+
+    let old_set = walk_dir(&old_file)?;
+    let new_set = walk_dir(&new_file)?;
+    let removed = sorted(old_set.difference(&new_set));
+    let added = sorted(new_set.difference(&old_set));
+    let kept = sorted(old_set.intersection(&new_set));
+    // New files are read twice, once to hash them and once to pack them
+    let total = 2 * new_set.iter().map(|x| metadata(Path::join(new_file.as_ref(), x)).map_or(0, |m| m.len())).sum::<u64>();
+    let mut done = 0;
+
+    // The hashes go before everything else in the archive, so they're computed first
+    progress.event(&Event::PhaseStarted(Phase::Hashing));
+    let mut source_hashes = Vec::new();
+    let mut target_hashes = Vec::new();
+    let mut changed = Vec::new();
+    let mut full_copies = Vec::new();
+    let mut moved = HashSet::new();
+    for x in &removed {
+        cancel.check()?;
+        let old_path = Path::join(old_file.as_ref(), x);
+        progress.event(&Event::FileStarted { phase: Phase::Hashing, path: x, size: metadata(&old_path).map_or(0, |m| m.len()) });
+        let (size, hash) = hash_file(&old_path)?;
+        source_hashes.push(HashEntry { kind: FileKind::Removed, hash, size, path: x.to_string() });
+    }
+    let removed_content: HashSet<(&str, u64)> = source_hashes.iter().map(|x| (x.hash.as_str(), x.size)).collect();
+    for x in &added {
+        cancel.check()?;
+        let new_path = Path::join(new_file.as_ref(), x);
+        progress.event(&Event::FileStarted { phase: Phase::Hashing, path: x, size: metadata(&new_path).map_or(0, |m| m.len()) });
+        let (size, hash) = hash_file(&new_path)?;
+        // Moved or copied files are made by apply from the removed file with the same content
+        if removed_content.contains(&(hash.as_str(), size)) {
+            moved.insert(*x);
+            done += size;
+        }
+        target_hashes.push(HashEntry { kind: FileKind::Added, hash, size, path: x.to_string() });
+        done += size;
+        progress.event(&Event::BytesProcessed { done, total });
+    }
+    let (moved, added): (Vec<&String>, Vec<&String>) = added.into_iter().partition(|x| moved.contains(x));
+    for x in &kept {
+        cancel.check()?;
+        let (old_path, new_path) = (Path::join(old_file.as_ref(), x), Path::join(new_file.as_ref(), x));
+        progress.event(&Event::FileStarted { phase: Phase::Hashing, path: x, size: metadata(&new_path).map_or(0, |m| m.len()) });
+        let ((old_size, old_hash), (new_size, new_hash), same) = hash_pair(&old_path, &new_path)?;
+        // Apply only touches files with diffs, those are the ones it has to check
+        if same {
+            done += new_size;
+        } else {
+            let kind = if options.wants_full_copy(x, new_size) { FileKind::Hybrid } else { FileKind::Diffed };
+            source_hashes.push(HashEntry { kind: FileKind::Diffed, hash: old_hash, size: old_size, path: x.to_string() });
+            target_hashes.push(HashEntry { kind, hash: new_hash, size: new_size, path: x.to_string() });
+            changed.push(*x);
+            if kind == FileKind::Hybrid {
+                full_copies.push(*x);
+            }
+        }
+        done += new_size;
+        progress.event(&Event::BytesProcessed { done, total });
+    }
+    source_hashes.sort_by(|a, b| a.path.cmp(&b.path));
+    target_hashes.sort_by(|a, b| a.path.cmp(&b.path));
+    if check_output_space {
+        // Moved files aren't stored, full copies are on top of their diffs
+        let needed = added.iter().chain(&full_copies).map(|x| metadata(Path::join(new_file.as_ref(), x)).map_or(0, |m| m.len())).sum();
+        let dir = output_name.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        check_space(dir, needed)?;
+    }
+
+    let mut result = zstd::Encoder::new(output, 1).map_err(|e| Error::io(output_name, e))?;
+    // Lets verify_patch and apply catch corrupted downloads
+    result.include_checksum(true).map_err(|e| Error::io(output_name, e))?;
+    {
+        let mut archive = Builder::new(&mut result);
+        progress.event(&Event::PhaseStarted(Phase::Packing));
+        let lvl = options.level;
+        let manifest = Manifest::new(lvl);
+        let mtime = manifest.created;
+        append_bytes(&mut archive, MANIFEST_NAME, manifest.to_text().as_bytes(), mtime).map_err(|e| Error::io(output_name, e))?;
+        append_bytes(&mut archive, SOURCE_HASHES_NAME, write_list(&source_hashes).as_bytes(), mtime).map_err(|e| Error::io(output_name, e))?;
+        append_bytes(&mut archive, TARGET_HASHES_NAME, write_list(&target_hashes).as_bytes(), mtime).map_err(|e| Error::io(output_name, e))?;
+
+        progress.event(&Event::PhaseStarted(Phase::CompilingChanged));
+        for x in changed {
+            let old_path = Path::join(old_file.as_ref(), x);
+            let new_path = Path::join(new_file.as_ref(), x);
+
+            let mut old = File::open(&old_path).map_err(|e| Error::io(&old_path, e))?;
+            let mut new = File::open(&new_path).map_err(|e| Error::io(&new_path, e))?;
+            let old_size = old.metadata().map_err(|e| Error::io(&old_path, e))?.len();
+            progress.event(&Event::FileStarted { phase: Phase::CompilingChanged, path: x, size: old_size });
+            let mut i = 0;
+            loop {
+                cancel.check()?;
+                i += 1;
+                let mut old_data = Vec::with_capacity(min(old_size as usize, CHUNK_SIZE));
+                let mut new_data = Vec::with_capacity(min(old_size as usize, CHUNK_SIZE));
+                let n = Read::by_ref(&mut old).take(CHUNK_SIZE as u64).read_to_end(&mut old_data).map_err(|e| Error::io(&old_path, e))?;
+                if old.stream_position().map_err(|e| Error::io(&old_path, e))?.eq(&old_size) {
+                    Read::by_ref(&mut new).read_to_end(&mut new_data).map_err(|e| Error::io(&new_path, e))?;
+                } else {
+                    Read::by_ref(&mut new).take(CHUNK_SIZE as u64).read_to_end(&mut new_data).map_err(|e| Error::io(&new_path, e))?;
+                }
+                done += new_data.len() as u64;
+                progress.event(&Event::BytesProcessed { done, total });
+                // An empty old file still gets a first chunk when the new one isn't
+                if n == 0 && (i > 1 || new_data.is_empty()) { break; }
+                if old_data.eq(&new_data) {
+                    continue;
+                }
+                // Rewritten or re-encrypted chunks diff badly, then the chunk alone is smaller and
+                // apply doesn't need to read the original. The diff goes first, it frees the old chunk
+                let patch_data = create(old_data, &new_data, lvl).map_err(|reason| Error::Compression { path: x.to_string(), reason })?;
+                let whole_data = compress(&new_data, lvl).map_err(|reason| Error::Compression { path: x.to_string(), reason })?;
+                drop(new_data);
+                let (patch_data, ext) = if whole_data.len() < patch_data.len() { (whole_data, WHOLE_EXT) } else { (patch_data, DIFF_EXT) };
+                let name = Path::new("diff_files").join(format!("{x}{ext}{i:0>3}"));
+                append_bytes(&mut archive, &name, &patch_data, mtime).map_err(|e| Error::io(output_name, e))?;
+                progress.event(&Event::ChunkDiffed { path: x, chunk: i });
+                if n < CHUNK_SIZE { break; }
+            }
+        }
+
+        // After the diffs, so apply can still fall back to them once a diff failed
+        for x in &full_copies {
+            cancel.check()?;
+            let new_path = Path::join(new_file.as_ref(), x);
+            let mut full_file = File::open(&new_path).map_err(|e| Error::io(&new_path, e))?;
+            progress.event(&Event::FileStarted { phase: Phase::Packing, path: x, size: full_file.metadata().map_or(0, |m| m.len()) });
+            archive.append_file(Path::new("full_files").join(x), &mut full_file).map_err(|e| Error::io(output_name, e))?;
+        }
+
+        progress.event(&Event::PhaseStarted(Phase::CompilingAdded));
+        for x in &added {
+            cancel.check()?;
+            let new_path = Path::join(new_file.as_ref(), x);
+            let mut added_file = File::open(&new_path).map_err(|e| Error::io(&new_path, e))?;
+            let size = added_file.metadata().map_or(0, |m| m.len());
+            progress.event(&Event::FileStarted { phase: Phase::CompilingAdded, path: x, size });
+            archive.append_file(Path::new("new_files").join(x), &mut added_file).map_err(|e| Error::io(output_name, e))?;
+            done += size;
+            progress.event(&Event::BytesProcessed { done, total });
+        }
+        let moved_files = write_paths(&moved);
+        append_bytes(&mut archive, "moved_files.txt", moved_files.as_bytes(), mtime).map_err(|e| Error::io(output_name, e))?;
+
+        progress.event(&Event::PhaseStarted(Phase::CompilingRemoved));
+        let rm_files = write_paths(&removed);
+        append_bytes(&mut archive, "rm_files.txt", rm_files.as_bytes(), mtime).map_err(|e| Error::io(output_name, e))?;
+    }
+    let mut output = result.finish().map_err(|e| Error::io(output_name, e))?;
+    output.flush().map_err(|e| Error::io(output_name, e))?;
+    Ok(output)
+}
+
+/// Applies `patch` onto the directory `path`, moving every replaced or removed file into `backup`.
+/// Every step is reported to `progress`, and logged to `backup/logs.txt`. Changes are recorded in
+/// `backup/journal.txt` so [`rollback_patch`] can undo them later. It only replaces the previous
+/// apply's journal once this one finished.
+///
+/// Added files that already exist are handled as `options` says, by default they're moved into
+/// `backup` too.
+///
+/// Applying is all or nothing: on any error, including cancellation through `cancel`, every change
+/// is rolled back and only the log is left behind. If the process died during a previous apply,
+/// what it did is rolled back first.
+///
+/// Before changing anything, fails with [`Error::NotEnoughSpace`] if `path` can't hold the patched
+/// and added files next to the backups. Patches made before hashes existed don't list sizes and
+/// skip that check.
+pub fn apply_patch(path: String, patch: String, options: &ApplyOptions, progress: &mut dyn Progress, cancel: &CancelToken) -> Result<()> {
+    if !metadata(&path).is_ok_and(|x| x.is_dir()) { return Err(Error::InvalidArgument("Path to update doesn't exist or is not a directory".to_string())) };
+    if !metadata(&patch).is_ok_and(|x| x.is_file()) { return Err(Error::InvalidArgument("Patch file doesn't exist".to_string())) };
+    let patch_file = File::open(&patch).map_err(|e| Error::io(&patch, e))?;
+    let size = patch_file.metadata().map_err(|e| Error::io(&patch, e))?.len();
+    apply_patch_from(path, patch_file, Some(size), options, progress, cancel)
+}
+
+/// Same as [`apply_patch`], but reads the patch from any stream, like stdin or a download still in
+/// progress. The archive is read front to back only once. `size` is the length of the stream if
+/// known, [`Event::BytesProcessed`] is only reported with it.
+pub fn apply_patch_from(path: String, patch: impl Read, size: Option<u64>, options: &ApplyOptions, progress: &mut dyn Progress, cancel: &CancelToken) -> Result<()> {
+    if !metadata(&path).is_ok_and(|x| x.is_dir()) { return Err(Error::InvalidArgument("Path to update doesn't exist or is not a directory".to_string())) };
+    let mut logs = String::new();
+    let mut report = |event: &Event| {
+        progress.event(event);
+        if !matches!(event, Event::BytesProcessed { .. }) {
+            logs.push_str(&format!("{event}\r\n"));
+        }
+    };
+    let root = Path::new(&path);
+    let backup_dir = root.join(BACKUP_DIR);
+    fs::create_dir_all(&backup_dir).map_err(|e| Error::io(&backup_dir, e))?;
+    let mut last_file_name = "".to_string();
+    let mut conflicts = Conflicts::default();
+    // Resolved once the target hashes tell which files have a full copy
+    let mut mismatches = Option::<Vec<HashEntry>>::None;
+    let mut diff_order = DiffOrder::default();
+    let mut current_file = Option::<File>::None;
+    let mut phase = Option::<Phase>::None;
+    let mut manifest = Option::<Manifest>::None;
+    let mut chunk_size = CHUNK_SIZE as u64;
+    // Patches made before hashes existed can't be verified
+    let mut targets = HashMap::<String, HashEntry>::new();
+    // Removed files moved files are made from, and the ones already taken by a move
+    let mut removed_sources = Vec::<HashEntry>::new();
+    let mut moved_away = HashSet::<String>::new();
+
+    let patch_file = CountingReader::new(patch);
+    let read = patch_file.count.clone();
+    let result = zstd::Decoder::new(patch_file).map_err(|e| Error::corrupt_io("couldn't start decompressing", e))?;
+    // A journal that wasn't finished means the process died mid-apply, the files it replaced are
+    // still in the backup dir and the ones it wrote may be partial
+    if let Some(journal) = interrupted(&backup_dir)? {
+        report(&Event::Warning("A previous apply was interrupted, restoring original files first".to_string()));
+        report(&Event::PhaseStarted(Phase::RollingBack));
+        journal.rollback(root, &mut report)?;
+    }
+    let mut undo = Journal::new(&backup_dir);
+    let result = (|| {
+        {
+            let mut a = Archive::new(result);
+            for file in a.entries().map_err(|e| Error::corrupt_io("couldn't list entries", e))? {
+                cancel.check()?;
+                let mut file = file.map_err(|e| Error::corrupt_io("couldn't read entry", e))?;
+
+                if file.header().entry_type() == EntryType::Directory {
+                    continue
+                }
+
+                let entry = EntryName::parse(file
+                    .path().map_err(|e| Error::corrupt_io("couldn't get entry path", e))?
+                    .to_str().ok_or(Error::corrupt("entry path isn't valid unicode"))?)?;
+
+                // The manifest comes first, patches without one predate it and use the defaults
+                if manifest.is_none() {
+                    let found = if entry == EntryName::Manifest {
+                        let mut text = String::new();
+                        file.read_to_string(&mut text).map_err(|e| Error::corrupt_io("couldn't read manifest", e))?;
+                        Manifest::parse(&text)?
+                    } else {
+                        Manifest::legacy()
+                    };
+                    found.check_supported()?;
+                    chunk_size = found.chunk_size;
+                    manifest = Some(found);
+                    if entry == EntryName::Manifest {
+                        continue
+                    }
+                }
+
+                if !matches!(entry, EntryName::SourceHashes | EntryName::TargetHashes) && let Some(mismatches) = mismatches.take() {
+                    resolve_mismatches(mismatches, options, &mut conflicts, &mut report)?;
+                }
+
+                let entry_phase = match entry {
+                    EntryName::SourceHashes => Some(Phase::Verifying),
+                    EntryName::Added(_) | EntryName::Moved => Some(Phase::Adding),
+                    EntryName::Diff { .. } | EntryName::Full(_) => Some(Phase::Patching),
+                    EntryName::Removed => Some(Phase::Removing),
+                    EntryName::Manifest | EntryName::TargetHashes => None,
+                };
+                if let Some(entry_phase) = entry_phase && phase != Some(entry_phase) {
+                    phase = Some(entry_phase);
+                    report(&Event::PhaseStarted(entry_phase));
+                }
+
+                match entry {
+                    EntryName::Manifest => return Err(Error::corrupt("manifest isn't the first entry")),
+                    EntryName::SourceHashes => {
+                        let mut text = String::new();
+                        file.read_to_string(&mut text).map_err(|e| Error::corrupt_io("couldn't read source hashes", e))?;
+                        let sources = parse_list(&text)?;
+                        mismatches = Some(check_sources(root, &sources, &mut report, cancel)?.into_iter().cloned().collect());
+                        removed_sources = sources.into_iter().filter(|x| x.kind == FileKind::Removed).collect();
+                    }
+                    EntryName::TargetHashes => {
+                        let mut text = String::new();
+                        file.read_to_string(&mut text).map_err(|e| Error::corrupt_io("couldn't read target hashes", e))?;
+                        targets = parse_list(&text)?.into_iter().map(|x| (x.path.clone(), x)).collect();
+                        conflicts.full_copies = targets.values().filter(|x| x.kind == FileKind::Hybrid).map(|x| x.path.clone()).collect();
+                        // Refused before anything changes, older patches only find out when they get there
+                        if options.existing_files == ExistingFiles::Fail {
+                            let existing: Vec<String> = sorted(targets.values().filter(|x| x.kind == FileKind::Added).map(|x| &x.path))
+                                .into_iter().filter(|x| root.join(x).exists()).cloned().collect();
+                            if !existing.is_empty() {
+                                return Err(Error::FileExists { files: existing });
+                            }
+                        }
+                        check_space(root, space_needed(targets.values()))?;
+                    }
+                    EntryName::Added(added_file) => {
+                        let added_file = added_file.as_str();
+                        report(&Event::FileStarted { phase: Phase::Adding, path: added_file, size: file.size() });
+                        if !make_room(root, added_file, options, &mut undo, &mut report)? {
+                            add_file(root, added_file, file, &mut undo)?;
+                            check_target(root, &targets, added_file)?;
+                        }
+                    },
+                    EntryName::Moved => {
+                        let mut text = String::new();
+                        file.read_to_string(&mut text).map_err(|e| Error::corrupt_io("couldn't read moved_files.txt", e))?;
+                        let mut moved = Vec::new();
+                        for moved_file in listed_paths(&text)? {
+                            cancel.check()?;
+                            let size = targets.get(&moved_file).ok_or_else(|| Error::corrupt(format!("moved file {moved_file} has no hash")))?.size;
+                            report(&Event::FileStarted { phase: Phase::Adding, path: &moved_file, size });
+                            if !make_room(root, &moved_file, options, &mut undo, &mut report)? {
+                                moved.push(moved_file);
+                            }
+                        }
+                        // Removed files that are still there matched their hash, or were left alone
+                        let (planned, unmovable) = plan_moves(moved, &targets, &removed_sources, |x| !conflicts.left_alone.contains(x) && root.join(x).is_file())?;
+                        if !unmovable.is_empty() {
+                            for moved_file in &unmovable {
+                                report(&Event::Warning(format!("No unmodified file to make {moved_file} from")));
+                            }
+                            return Err(Error::ApplyFailed { files: unmovable });
+                        }
+                        for PlannedMove { path: moved_file, from, take } in planned {
+                            cancel.check()?;
+                            if let Some(parent) = Path::new(&moved_file).parent() {
+                                create_dirs(root, parent, &mut undo)?;
+                            }
+                            let (from_path, moved_path) = (root.join(&from), root.join(&moved_file));
+                            if take {
+                                undo.push(Undo::Moved { path: from.clone(), backup: PathBuf::from(moved_file) })?;
+                                fs::rename(&from_path, &moved_path).map_err(|e| Error::io(&from_path, e))?;
+                                moved_away.insert(from);
+                            } else {
+                                undo.push(Undo::Created(moved_file))?;
+                                fs::copy(&from_path, &moved_path).map_err(|e| Error::io(&moved_path, e))?;
+                            }
+                        }
+                    }
+                    EntryName::Full(full_file) => {
+                        // Files that matched were patched from their diffs already
+                        if conflicts.needs_full.remove(&full_file) {
+                            report(&Event::FileStarted { phase: Phase::Patching, path: &full_file, size: file.size() });
+                            if root.join(&full_file).exists() {
+                                move_file(root, &full_file, &Path::new(BACKUP_DIR).join("full_files"), &mut undo)?;
+                            }
+                            add_file(root, &full_file, file, &mut undo)?;
+                            check_target(root, &targets, &full_file)?;
+                        }
+                    }
+                    EntryName::Diff { path: new_file_name, chunk: i, whole } => {
+                        let diff_files_path = Path::new(BACKUP_DIR).join("diff_files");
+                        // Conflicting files are left alone or wait for their full copy
+                        let skip = conflicts.skips(&new_file_name);
+                        if diff_order.next(&new_file_name, i)? {
+                            if let Some(old_file) = current_file.take() {
+                                finish_patched(&root.join(&last_file_name), &old_file)?;
+                                check_target(root, &targets, &last_file_name)?;
+                            }
+                            if !skip {
+                                create_dirs(root, &diff_files_path, &mut undo)?;
+                                move_file(root, &new_file_name, &diff_files_path, &mut undo)?;
+                                undo.push(Undo::Created(new_file_name.clone()))?;
+                                let backup_path = root.join(&diff_files_path).join(&new_file_name);
+                                let old_file = File::open(&backup_path).map_err(|e| Error::io(&backup_path, e))?;
+                                let size = old_file.metadata().map_or(0, |m| m.len());
+                                report(&Event::FileStarted { phase: Phase::Patching, path: &new_file_name, size });
+                                current_file = Some(old_file);
+                                last_file_name = new_file_name.clone();
+                            }
+                        }
+                        if !skip {
+                            let mut old_file = current_file.as_ref().ok_or_else(|| Error::corrupt(format!("no current file for {new_file_name}")))?;
+                            let backup_path = root.join(&diff_files_path).join(&new_file_name);
+                            let new_path = root.join(&new_file_name);
+
+                            let mut patch_data = Vec::with_capacity(file.size() as usize);
+                            let mut new_file = fs::OpenOptions::new().create(true).append(true).open(&new_path).map_err(|e| Error::io(&new_path, e))?;
+
+                            let missing_chunks = i - 1 - old_file.stream_position().map_err(|e| Error::io(&backup_path, e))? / chunk_size;
+                            if missing_chunks > 0 {
+                                let mut take = Read::by_ref(&mut old_file).take(missing_chunks * chunk_size);
+                                std::io::copy(&mut take, &mut new_file).map_err(|e| Error::io(&new_path, e))?;
+                            }
+
+                            let mut old_data = Vec::new();
+                            if whole {
+                                old_file.seek(SeekFrom::Current(chunk_size as i64)).map_err(|e| Error::io(&backup_path, e))?;
+                            } else {
+                                old_data.reserve(min(old_file.metadata().map_err(|e| Error::io(&backup_path, e))?.len(), chunk_size) as usize);
+                                Read::by_ref(&mut old_file).take(chunk_size).read_to_end(&mut old_data).map_err(|e| Error::io(&backup_path, e))?;
+                            }
+                            let ext = if whole { WHOLE_EXT } else { DIFF_EXT };
+                            file.read_to_end(&mut patch_data).map_err(|e| Error::corrupt_io(format!("couldn't read {ext}{i} for {new_file_name}"), e))?;
+                            match apply(&old_data, patch_data) {
+                                Ok(result) => {
+                                    new_file.write_all(&result).map_err(|e| Error::io(&new_path, e))?;
+                                    report(&Event::ChunkPatched { path: &new_file_name, chunk: i });
+                                }
+                                Err(_) => {
+                                    let policy = options.conflict_policy(&new_file_name);
+                                    if policy != ConflictPolicy::Fail {
+                                        report(&Event::Warning(format!("Couldn't apply the diffs of {new_file_name}")));
+                                    }
+                                    if !conflicts.resolve(&new_file_name, policy, false, &mut report) {
+                                        return Err(Error::ApplyFailed { files: vec![new_file_name] });
+                                    }
+                                    drop(new_file);
+                                    current_file = None;
+                                    restore_original(root, &new_file_name, &diff_files_path, &mut undo)?;
+                                }
+                            }
+                        }
+                    },
+                    EntryName::Removed => {
+                        let rm_files_path = Path::new(BACKUP_DIR).join("rm_files");
+                        create_dirs(root, &rm_files_path, &mut undo)?;
+                        let mut text = String::new();
+                        file.read_to_string(&mut text).map_err(|e| Error::corrupt_io("couldn't read rm_files.txt", e))?;
+                        // Every line is checked before the first file moves
+                        let rem_files = listed_paths(&text)?;
+                        for rem_file in rem_files.into_iter().filter(|x| !conflicts.left_alone.contains(x) && !moved_away.contains(x)) {
+                            cancel.check()?;
+                            let full_path = root.join(&rem_file);
+                            // Only a file that's already gone is fine, failing to move anything else rolls back
+                            let size = match metadata(&full_path) {
+                                Ok(x) => x.len(),
+                                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                                    report(&Event::Warning(format!("{rem_file} was already removed")));
+                                    continue
+                                }
+                                Err(e) => return Err(Error::io(&full_path, e)),
+                            };
+                            report(&Event::FileStarted { phase: Phase::Removing, path: &rem_file, size });
+                            move_file(root, &rem_file, &rm_files_path, &mut undo)?;
+                        }
+                    }
+                }
+                if let Some(total) = size {
+                    report(&Event::BytesProcessed { done: read.get(), total });
+                }
+            }
+        }
+        if let Some(mismatches) = mismatches.take() {
+            resolve_mismatches(mismatches, options, &mut conflicts, &mut report)?;
+        }
+        if let Some(old_file) = current_file.take() {
+            finish_patched(&root.join(&last_file_name), &old_file)?;
+            check_target(root, &targets, &last_file_name)?;
+        }
+        if let Some(file) = sorted(conflicts.needs_full.iter()).first() {
+            return Err(Error::corrupt(format!("full copy of {file} is missing")));
+        }
+        undo.finish()
+    })();
+
+    let mut restored = Ok(());
+    if let Err(e) = &result {
+        match e {
+            _ if undo.is_empty() => {}
+            Error::Cancelled => report(&Event::Warning("Cancelled, restoring original files".to_string())),
+            _ => report(&Event::Warning(format!("{e}, restoring original files"))),
+        }
+        restored = undo.rollback(root, &mut report);
+        if let Err(e) = &restored {
+            report(&Event::Warning(format!("Couldn't restore every file, run a rollback to try again: {e}")));
+        }
+    }
+
+    let log_path = backup_dir.join("logs.txt");
+    fs::write(&log_path, logs).map_err(|e| Error::io(&log_path, e))?;
+    // Only the last apply's conflicts are worth keeping
+    let conflicts_path = backup_dir.join("conflicts.txt");
+    if result.is_ok() && !conflicts.kept.is_empty() {
+        let kept: String = conflicts.kept.iter().map(|x| format!("{x}\n")).collect();
+        fs::write(&conflicts_path, kept).map_err(|e| Error::io(&conflicts_path, e))?;
+    } else if result.is_ok() {
+        let _ = fs::remove_file(&conflicts_path);
+    }
+    restored?;
+    result
+}
+
+/// Undoes the last patch applied to the directory `path`, using the journal it left in `backup`.
+/// An apply that was interrupted counts as the last one. Every restored file is reported to
+/// `progress`.
+///
+/// A rollback that stopped halfway carries on from there when run again.
+pub fn rollback_patch(path: String, progress: &mut dyn Progress) -> Result<()> {
+    let backup_dir = Path::new(&path).join(BACKUP_DIR);
+    let journal_path = backup_dir.join(JOURNAL_NAME);
+    let journal = match interrupted(&backup_dir)? {
+        Some(journal) => journal,
+        None if journal_path.is_file() => load(&journal_path)?.0,
+        None => return Err(Error::InvalidArgument(format!("No applied patch to roll back in {path}"))),
+    };
+    progress.event(&Event::PhaseStarted(Phase::RollingBack));
+    journal.rollback(Path::new(&path), &mut |event: &Event| progress.event(event))
+}
+
+fn append_bytes(archive: &mut Builder<impl Write>, name: impl AsRef<Path>, data: &[u8], mtime: u64) -> std::io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    header.set_cksum();
+    archive.append_data(&mut header, name, data)
+}
+
+/// Compares the files listed in a source hash list with the ones in the current directory, and
+/// returns those that don't match. Removed files may be missing already.
+pub(crate) fn check_sources<'a>(root: &Path, entries: &'a [HashEntry], report: &mut impl FnMut(&Event), cancel: &CancelToken) -> Result<Vec<&'a HashEntry>> {
+    let mut mismatches = Vec::new();
+    for entry in entries {
+        cancel.check()?;
+        report(&Event::FileStarted { phase: Phase::Verifying, path: &entry.path, size: entry.size });
+        let full_path = root.join(&entry.path);
+        let size = match metadata(&full_path) {
+            Ok(x) => x.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if entry.kind == FileKind::Diffed {
+                    report(&Event::Warning(format!("{} is missing", entry.path)));
+                    mismatches.push(entry);
+                }
+                continue
+            }
+            Err(e) => return Err(Error::io(&full_path, e)),
+        };
+        if size != entry.size || hash_file(&full_path)?.1 != entry.hash {
+            report(&Event::Warning(format!("{} doesn't match the version the patch was made from", entry.path)));
+            mismatches.push(entry);
+        }
+    }
+    Ok(mismatches)
+}
+
+/// How apply makes a file listed in `moved_files.txt`.
+pub(crate) struct PlannedMove {
+    pub(crate) path: String,
+    /// Removed file with the same content
+    pub(crate) from: String,
+    /// Nothing else is made from `from` afterwards, so it's moved to `path` instead of copied
+    pub(crate) take: bool,
+}
+
+/// Picks a removed file among `sources` with the content of each of `moved`, in order, skipping
+/// those `usable` refuses. Also returns the moved files nothing could be picked for.
+pub(crate) fn plan_moves(moved: Vec<String>, targets: &HashMap<String, HashEntry>, sources: &[HashEntry], usable: impl Fn(&str) -> bool) -> Result<(Vec<PlannedMove>, Vec<String>)> {
+    if moved.is_empty() {
+        return Ok((Vec::new(), Vec::new()));
+    }
+    let mut by_content = HashMap::<(&str, u64), &str>::new();
+    for source in sources.iter().filter(|x| x.kind == FileKind::Removed && usable(&x.path)) {
+        by_content.entry((source.hash.as_str(), source.size)).or_insert(source.path.as_str());
+    }
+    let mut picked = Vec::with_capacity(moved.len());
+    let mut unmovable = Vec::new();
+    let mut uses = HashMap::<&str, usize>::new();
+    for path in moved {
+        let target = targets.get(&path).ok_or_else(|| Error::corrupt(format!("moved file {path} has no hash")))?;
+        match by_content.get(&(target.hash.as_str(), target.size)) {
+            Some(&from) => {
+                *uses.entry(from).or_default() += 1;
+                picked.push((path, from));
+            }
+            None => unmovable.push(path),
+        }
+    }
+    let planned = picked.into_iter().map(|(path, from)| {
+        let left = uses.entry(from).or_insert(1);
+        *left -= 1;
+        PlannedMove { path, from: from.to_string(), take: *left == 0 }
+    }).collect();
+    Ok((planned, unmovable))
+}
+
+/// Files apply doesn't patch from their diffs because they don't match the version the patch was
+/// made from, sorted by [`ConflictPolicy`].
+#[derive(Default)]
+struct Conflicts {
+    /// Files to leave as they are, their diffs and removal are ignored
+    left_alone: HashSet<String>,
+    /// Files to replace with their full copy once it comes, their diffs are ignored
+    needs_full: HashSet<String>,
+    /// Files kept with [`ConflictPolicy::Keep`], listed in `conflicts.txt` afterwards
+    kept: Vec<String>,
+    /// Files the patch has a full copy of
+    full_copies: HashSet<String>,
+}
+
+impl Conflicts {
+    /// Deals with a conflict on `path` as `policy` says, returns false if it says to fail instead.
+    fn resolve(&mut self, path: &str, policy: ConflictPolicy, removed: bool, report: &mut impl FnMut(&Event)) -> bool {
+        match policy.for_file(removed, self.full_copies.contains(path)) {
+            ConflictPolicy::Fail if policy == ConflictPolicy::Overwrite => {
+                report(&Event::Warning(format!("The patch has no full copy of {path}")));
+                return false;
+            }
+            ConflictPolicy::Fail => return false,
+            ConflictPolicy::Skip => {
+                report(&Event::Warning(format!("Skipping {path}")));
+                self.left_alone.insert(path.to_string());
+            }
+            ConflictPolicy::Keep => {
+                report(&Event::Warning(format!("Conflict on {path}, keeping the local version")));
+                self.left_alone.insert(path.to_string());
+                self.kept.push(path.to_string());
+            }
+            ConflictPolicy::Overwrite => {
+                report(&Event::Warning(format!("Replacing {path} with its full copy from the patch")));
+                self.needs_full.insert(path.to_string());
+            }
+        }
+        true
+    }
+
+    fn skips(&self, path: &str) -> bool {
+        self.left_alone.contains(path) || self.needs_full.contains(path)
+    }
+}
+
+/// Deals with the files that don't match the version the patch was made from, fails if any of them
+/// can't be dealt with.
+fn resolve_mismatches(mismatches: Vec<HashEntry>, options: &ApplyOptions, conflicts: &mut Conflicts, report: &mut impl FnMut(&Event)) -> Result<()> {
+    let mut failed = Vec::new();
+    for entry in mismatches {
+        if !conflicts.resolve(&entry.path, options.conflict_policy(&entry.path), entry.kind == FileKind::Removed, report) {
+            failed.push(entry.path);
+        }
+    }
+    if !failed.is_empty() {
+        return Err(Error::SourceMismatch { files: failed });
+    }
+    Ok(())
+}
+
+/// Disk space applying the files in `targets` takes on top of what the target already uses. Every
+/// file they replace is moved to the backup dir and keeps its space.
+fn space_needed<'a>(targets: impl Iterator<Item = &'a HashEntry>) -> u64 {
+    targets.map(|x| x.size).sum()
+}
+
+/// Copies the chunks of the original file left after the last diff at the end of the patched one.
+fn finish_patched(path: &Path, mut old_file: &File) -> Result<()> {
+    let mut new_file = fs::OpenOptions::new().create(true).append(true).open(path).map_err(|e| Error::io(path, e))?;
+    std::io::copy(&mut old_file, &mut new_file).map_err(|e| Error::io(path, e))?;
+    Ok(())
+}
+
+/// Checks a file written by apply against the hash the patch expects for it.
+fn check_target(root: &Path, targets: &HashMap<String, HashEntry>, path: &str) -> Result<()> {
+    let Some(entry) = targets.get(path) else { return Ok(()) };
+    let full_path = root.join(path);
+    let size = metadata(&full_path).map_err(|e| Error::io(&full_path, e))?.len();
+    if size != entry.size || hash_file(&full_path)?.1 != entry.hash {
+        return Err(Error::TargetMismatch { path: path.to_string() });
+    }
+    Ok(())
+}
+
+/// Puts the original of `file` back from `backup_dir` after its diffs failed, leaving the journal
+/// able to roll back to it.
+fn restore_original(root: &Path, file: &str, backup_dir: &Path, undo: &mut Journal) -> Result<()> {
+    let (full_path, backup) = (root.join(file), backup_dir.join(file));
+    fs::remove_file(&full_path).map_err(|e| Error::io(&full_path, e))?;
+    // Recorded first, rolling back a move that didn't happen is skipped
+    let backup_name = backup.to_str().ok_or_else(|| Error::NonUnicodePath(backup.clone()))?.to_string();
+    undo.push(Undo::Moved { path: backup_name, backup: PathBuf::from(file) })?;
+    fs::rename(root.join(&backup), &full_path).map_err(|e| Error::io(&full_path, e))
+}
+
+/// `dir` and its parents that don't exist in `root` yet, outermost first.
+fn missing_dirs(root: &Path, dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = dir.ancestors()
+        .take_while(|x| !x.as_os_str().is_empty() && !root.join(x).exists())
+        .map(Path::to_path_buf)
+        .collect();
+    dirs.reverse();
+    dirs
+}
+
+fn create_dirs(root: &Path, dir: &Path, undo: &mut Journal) -> Result<()> {
+    undo.extend(missing_dirs(root, dir).into_iter().map(Undo::CreatedDir))?;
+    let full_path = root.join(dir);
+    fs::create_dir_all(&full_path).map_err(|e| Error::io(&full_path, e))
+}
+
+fn move_file(root: &Path, file: &str, new_dir: &Path, undo: &mut Journal) -> Result<()> {
+    let backup = new_dir.join(file);
+    if let Some(parent) = backup.parent() {
+        create_dirs(root, parent, undo)?;
+    }
+    let backup_path = root.join(&backup);
+    if backup_path.is_file() {
+        set_aside(root, &backup, undo)?;
+    }
+    // Recorded first, so the file can't end up in the backup dir without the journal knowing
+    undo.push(Undo::Moved { path: file.to_string(), backup })?;
+    let full_path = root.join(file);
+    fs::rename(&full_path, &backup_path).map_err(|e| Error::io(&full_path, e))
+}
+
+/// Moves `backup`, left by the previous apply, out of the way of this one's. Its journal still needs
+/// it until this apply finishes, and it would be mistaken for this one's after a crash.
+fn set_aside(root: &Path, backup: &Path, undo: &mut Journal) -> Result<()> {
+    let aside = Path::new(BACKUP_DIR).join(PREVIOUS_DIR).join(backup.strip_prefix(BACKUP_DIR).unwrap_or(backup));
+    if let Some(parent) = aside.parent() {
+        create_dirs(root, parent, undo)?;
+    }
+    undo.push(Undo::SetAside { path: backup.to_path_buf(), aside: aside.clone() })?;
+    let (backup_path, aside_path) = (root.join(backup), root.join(&aside));
+    fs::rename(&backup_path, &aside_path).map_err(|e| Error::io(&backup_path, e))
+}
+
+/// Deals with an existing `file` in the way of an added one as `options` say, returns whether to
+/// leave it and skip the added file.
+fn make_room(root: &Path, file: &str, options: &ApplyOptions, undo: &mut Journal, report: &mut impl FnMut(&Event)) -> Result<bool> {
+    if !root.join(file).exists() {
+        return Ok(false);
+    }
+    match options.existing_files {
+        ExistingFiles::Overwrite => {
+            report(&Event::Warning(format!("{file} already exists, moving it to the backup dir")));
+            move_file(root, file, &Path::new(BACKUP_DIR).join("new_files"), undo)?;
+            Ok(false)
+        }
+        ExistingFiles::Skip => {
+            report(&Event::Warning(format!("{file} already exists, skipping it")));
+            Ok(true)
+        }
+        ExistingFiles::Fail => Err(Error::FileExists { files: vec![file.to_string()] }),
+    }
+}
+
+/// Writes `entry` to `file`, which doesn't exist.
+fn add_file(root: &Path, file: &str, mut entry: Entry<impl Read>, undo: &mut Journal) -> Result<()> {
+    if let Some(parent) = Path::new(file).parent() {
+        undo.extend(missing_dirs(root, parent).into_iter().map(Undo::CreatedDir))?;
+    }
+    undo.push(Undo::Created(file.to_string()))?;
+    let added_path = root.join(file);
+    if let Some(parent) = added_path.parent() {
+        fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+    }
+    let mut test = File::create(&added_path).map_err(|e| Error::io(&added_path, e))?;
+    std::io::copy(&mut entry, &mut test).map_err(|e| Error::io(&added_path, e))?;
+    Ok(())
+}
+
+fn walk_dir(dir: &String) -> Result<HashSet<String>> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file() && !e.path().to_str().unwrap_or(r".patchiniored").contains(r".patchiniored"))
+        .map(|x| {
+            let path = x.path().strip_prefix(dir).unwrap_or(x.path());
+            Ok(path.to_str().ok_or_else(|| Error::NonUnicodePath(x.path().to_path_buf()))?.to_string())
+        })
+        .collect()
+}
+
+/// Rebuilds a chunk from its diff against `old_data`, chunks stored whole get an empty one.
+fn apply(old_data: &[u8], patch_data: Vec<u8>) -> std::result::Result<Vec<u8>, ()> {
+    let mut dict = zstd_safe::DCtx::create();
+    let frame_content_size = zstd_safe::get_frame_content_size(&patch_data).map_err(|_| ())?.ok_or(())?;
+    let mut new_data = Vec::with_capacity(frame_content_size as usize);
+
+    dict.decompress_using_dict(&mut new_data, &patch_data, old_data).map_err(|_| ())?;
+
+    Ok(new_data)
+}
+
+fn create(old_data: Vec<u8>, new_data: &[u8], lvl: i32) -> std::result::Result<Vec<u8>, String> {
+    let high_bit = fio_high_bit64(old_data.len());
+    let window_log = (high_bit+1).clamp(10, 31);
+
+    let mut dict = zstd_safe::CCtx::create();
+    dict.set_parameter(CParameter::CompressionLevel(lvl)).map_err(|e| format!("Couldn't set compression level: {}", zstd_safe::get_error_name(e)))?;
+    dict.set_parameter(CParameter::WindowLog(window_log)).map_err(|e| format!("Couldn't set window log {window_log}: {}", zstd_safe::get_error_name(e)))?;
+    dict.set_parameter(CParameter::EnableLongDistanceMatching(true)).map_err(|e| format!("Couldn't enable long distance matching: {}", zstd_safe::get_error_name(e)))?;
+    dict.ref_prefix(&old_data).map_err(|e| format!("Couldn't apply ref prefix: {}", zstd_safe::get_error_name(e)))?;
+
+    let compress_bound = zstd_safe::compress_bound(new_data.len());
+
+    let mut patch_data = Vec::with_capacity(compress_bound);
+    dict.compress2(&mut patch_data, new_data).map_err(|e| format!("Couldn't create zspatch data: {}", zstd_safe::get_error_name(e)))?;
+
+    Ok(patch_data)
+}
+
+/// Compresses a chunk on its own, for when that's smaller than its diff.
+fn compress(new_data: &[u8], lvl: i32) -> std::result::Result<Vec<u8>, String> {
+    let mut ctx = zstd_safe::CCtx::create();
+    ctx.set_parameter(CParameter::CompressionLevel(lvl)).map_err(|e| format!("Couldn't set compression level: {}", zstd_safe::get_error_name(e)))?;
+
+    let mut whole_data = Vec::with_capacity(zstd_safe::compress_bound(new_data.len()));
+    ctx.compress2(&mut whole_data, new_data).map_err(|e| format!("Couldn't compress chunk: {}", zstd_safe::get_error_name(e)))?;
+
+    Ok(whole_data)
+}
+
+fn fio_high_bit64(mut x: usize) -> u32 {
+    let mut count = 0;
+    x >>= 1;
+    while x != 0 {
+        x >>= 1;
+        count += 1;
+    }
+    count
+}
\ No newline at end of file
@@ -0,0 +1,121 @@
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+
+/// Steps of [`create_patch`](crate::create_patch) and [`apply_patch`](crate::apply_patch). Create
+/// goes through `Hashing`, `Packing`, `CompilingChanged`, `CompilingAdded` and `CompilingRemoved`,
+/// apply through `Verifying`, `Patching`, `Adding` and `Removing`. Undoing an apply is `RollingBack`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Listing files only present in the old directory
+    CompilingRemoved,
+    /// Storing files only present in the new directory
+    CompilingAdded,
+    /// Diffing files present in both directories
+    CompilingChanged,
+    /// Hashing the files the patch will touch
+    Hashing,
+    /// Writing the .patchini file
+    Packing,
+    /// Checking the files to patch are the ones the patch was made from
+    Verifying,
+    /// Rebuilding changed files from their diffs
+    Patching,
+    /// Extracting added files
+    Adding,
+    /// Moving removed files to the backup dir
+    Removing,
+    /// Putting back the files an apply replaced or removed
+    RollingBack,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::CompilingRemoved => "Compiling removed files",
+            Phase::CompilingAdded => "Compiling added files",
+            Phase::CompilingChanged => "Compiling changed files",
+            Phase::Hashing => "Hashing files",
+            Phase::Packing => "Generating patch file",
+            Phase::Verifying => "Verifying files",
+            Phase::Patching => "Patching changed files",
+            Phase::Adding => "Adding files",
+            Phase::Removing => "Removing files",
+            Phase::RollingBack => "Restoring original files",
+        })
+    }
+}
+
+/// Something that happened while creating or applying a patch.
+///
+/// `Display` gives the line written to logs, [`Event::BytesProcessed`] is meant for progress bars
+/// and is usually not worth logging.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    PhaseStarted(Phase),
+    /// A file is about to be handled by `phase`, `size` is its size on disk (or in the patch for
+    /// added files)
+    FileStarted { phase: Phase, path: &'a str, size: u64 },
+    /// `done` out of `total` bytes were handled. For creation those are bytes of the new
+    /// directory, for application bytes of the patch file.
+    BytesProcessed { done: u64, total: u64 },
+    /// Chunk number `chunk` of `path` changed and was diffed
+    ChunkDiffed { path: &'a str, chunk: u64 },
+    /// Chunk number `chunk` of `path` was rebuilt from its diff
+    ChunkPatched { path: &'a str, chunk: u64 },
+    /// Something went wrong but the operation carries on
+    Warning(String),
+}
+
+impl Display for Event<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::PhaseStarted(phase) => write!(f, "{phase}"),
+            Event::FileStarted { phase, path, .. } => match phase {
+                Phase::CompilingAdded | Phase::Adding => write!(f, "adding file {path}"),
+                Phase::CompilingChanged => write!(f, "diffing file {path}"),
+                Phase::Verifying => write!(f, "verifying file {path}"),
+                Phase::Patching => write!(f, "patching file {path}"),
+                Phase::CompilingRemoved | Phase::Removing => write!(f, "removing file {path}"),
+                Phase::RollingBack => write!(f, "restoring file {path}"),
+                Phase::Hashing => write!(f, "hashing file {path}"),
+                Phase::Packing => write!(f, "packing file {path}"),
+            },
+            Event::BytesProcessed { done, total } => write!(f, "{done}/{total} bytes"),
+            Event::ChunkDiffed { path, chunk } => write!(f, "diffed {path} part {chunk}"),
+            Event::ChunkPatched { path, chunk } => write!(f, "applied diff {path} part {chunk}"),
+            Event::Warning(text) => write!(f, "Warning: {text}"),
+        }
+    }
+}
+
+/// Receives the [`Event`]s of a running operation, closures taking an `&Event` implement it.
+pub trait Progress {
+    fn event(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event)> Progress for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Reader keeping track of how many bytes went through it, so apply can report progress on the
+/// compressed patch.
+pub(crate) struct CountingReader<R> {
+    pub(crate) inner: R,
+    pub(crate) count: std::rc::Rc<std::cell::Cell<u64>>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self { inner, count: Default::default() }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,59 @@
+use crate::error::{Error, Result};
+use std::io;
+use std::path::Path;
+
+/// Fails with [`Error::NotEnoughSpace`] if the volume holding `path` has less than `needed` bytes
+/// free. Volumes that can't tell their free space are let through.
+pub(crate) fn check_space(path: &Path, needed: u64) -> Result<()> {
+    match available_space(path) {
+        Ok(available) if available < needed => Err(Error::NotEnoughSpace { path: path.to_path_buf(), needed, available }),
+        _ => Ok(()),
+    }
+}
+
+/// Bytes the current user can still write on the volume holding `path`.
+#[cfg(unix)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // Both are 32 bits wide on some platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Bytes the current user can still write on the volume holding `path`.
+#[cfg(windows)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0;
+    if unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_against_free_space() {
+        let dir = std::env::temp_dir();
+        assert!(check_space(&dir, 0).is_ok());
+        let result = check_space(&dir, u64::MAX);
+        assert!(matches!(&result, Err(Error::NotEnoughSpace { needed: u64::MAX, available, .. }) if *available < u64::MAX), "{result:?}");
+    }
+}
@@ -0,0 +1,66 @@
+//! Cancelling through the token stops at the next file, an apply then restores the original tree
+//! and a create removes its partial patch.
+
+mod common;
+
+use common::{has_journal, path, tree, write, Fixture};
+use patchini::{apply_patch, create_patch, ApplyOptions, CancelToken, CreateOptions, Error, Event};
+use std::sync::OnceLock;
+
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| Fixture::new("cancellation", &CreateOptions::default(), |old, new| {
+        write(old, "same.txt", b"unchanged");
+        write(old, "sub/changed.bin", &(0..50_000u32).flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>());
+        write(old, "sub/other.txt", b"other v1");
+        write(old, "sub/removed.txt", b"removed");
+        write(new, "same.txt", b"unchanged");
+        write(new, "sub/changed.bin", &(0..50_000u32).flat_map(|x| (x ^ 7).to_le_bytes()).collect::<Vec<_>>());
+        write(new, "sub/other.txt", b"other v2");
+        write(new, "added/deep/added.txt", b"added");
+    }))
+}
+
+/// Progress cancelling `cancel` when the `cancel_at`th file starts.
+fn cancel_at(cancel: &CancelToken, cancel_at: usize) -> impl FnMut(&Event) + '_ {
+    let mut started = 0;
+    move |event: &Event| {
+        if matches!(event, Event::FileStarted { .. }) {
+            started += 1;
+            if started == cancel_at {
+                cancel.cancel();
+            }
+        }
+    }
+}
+
+#[test]
+fn cancelled_apply_restores_original() {
+    let fixture = fixture();
+    let mut cancels = 0;
+    for at in 1.. {
+        let target = fixture.target(&format!("apply_{at}"));
+        let cancel = CancelToken::new();
+        let result = apply_patch(path(&target), path(&fixture.patch), &ApplyOptions::default(), &mut cancel_at(&cancel, at), &cancel);
+        // Cancelling during the last file comes too late to stop anything
+        if result.is_ok() {
+            assert_eq!(tree(&target), tree(&fixture.new));
+            break;
+        }
+        cancels += 1;
+        assert!(matches!(result, Err(Error::Cancelled)), "cancel at {at}: {result:?}");
+        assert_eq!(tree(&target), tree(&fixture.old), "cancel at {at}");
+        assert!(!has_journal(&target), "cancel at {at}");
+    }
+    assert!(cancels > 3);
+}
+
+#[test]
+fn cancelled_create_leaves_no_patch() {
+    let fixture = fixture();
+    let output = fixture.dir.join("cancelled.patchini");
+    let cancel = CancelToken::new();
+    let result = create_patch(path(&fixture.old), path(&fixture.new), &CreateOptions::default(), path(&output), &mut cancel_at(&cancel, 2), &cancel);
+    assert!(matches!(result, Err(Error::Cancelled)), "{result:?}");
+    assert!(!output.exists());
+}
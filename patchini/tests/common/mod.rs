@@ -0,0 +1,118 @@
+//! Trees, patches and comparisons shared by the integration tests.
+
+#![allow(dead_code)]
+
+use patchini::{apply_patch, create_patch, ApplyOptions, CancelToken, CreateOptions, Event};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// An old and a new tree in a fresh temporary directory, and the patch between them.
+pub struct Fixture {
+    pub dir: PathBuf,
+    pub old: PathBuf,
+    pub new: PathBuf,
+    pub patch: PathBuf,
+}
+
+impl Fixture {
+    /// Has `build` write the old and new trees it's given, then creates the patch.
+    pub fn new(name: &str, options: &CreateOptions, build: impl FnOnce(&Path, &Path)) -> Fixture {
+        let dir = std::env::temp_dir().join(format!("patchini-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (old, new) = (dir.join("old"), dir.join("new"));
+        fs::create_dir_all(&old).unwrap();
+        fs::create_dir_all(&new).unwrap();
+        build(&old, &new);
+        let patch = dir.join("test.patchini");
+        create_patch(path(&old), path(&new), options, path(&patch), &mut |_: &Event| {}, &CancelToken::new()).unwrap();
+        Fixture { dir, old, new, patch }
+    }
+
+    /// Copies the old tree, empty directories included, to a fresh target directory.
+    pub fn target(&self, name: &str) -> PathBuf {
+        let target = self.dir.join(name);
+        let _ = fs::remove_dir_all(&target);
+        fs::create_dir_all(&target).unwrap();
+        for (name, data) in tree(&self.old) {
+            match data {
+                Some(data) => write(&target, &name, &data),
+                None => fs::create_dir_all(target.join(name)).unwrap(),
+            }
+        }
+        target
+    }
+
+    pub fn apply(&self, target: &Path, options: &ApplyOptions) -> patchini::Result<()> {
+        apply_patch(path(target), path(&self.patch), options, &mut |_: &Event| {}, &CancelToken::new())
+    }
+}
+
+pub fn write(root: &Path, name: &str, data: &[u8]) {
+    let path = root.join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, data).unwrap();
+}
+
+pub fn path(path: &Path) -> String {
+    path.to_str().unwrap().to_string()
+}
+
+/// Whether an apply left a journal in `target`, finished or not.
+pub fn has_journal(target: &Path) -> bool {
+    ["journal.txt", "journal.txt.new"].iter().any(|x| target.join("backup").join(x).exists())
+}
+
+/// Every file and directory in `root` except the backup dir, by relative path with `/`,
+/// directories without contents.
+pub fn tree(root: &Path) -> BTreeMap<String, Option<Vec<u8>>> {
+    fn walk(root: &Path, dir: &Path, files: &mut BTreeMap<String, Option<Vec<u8>>>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.strip_prefix(root).unwrap().to_str().unwrap().replace('\\', "/");
+            if name == "backup" {
+                continue
+            }
+            if path.is_dir() {
+                files.insert(name, None);
+                walk(root, &path, files);
+            } else {
+                files.insert(name, Some(fs::read(&path).unwrap()));
+            }
+        }
+    }
+    let mut files = BTreeMap::new();
+    walk(root, root, &mut files);
+    files
+}
+
+/// Every file in `root` except the backup dir, by relative path with `/`.
+pub fn files(root: &Path) -> BTreeMap<String, Vec<u8>> {
+    tree(root).into_iter().filter_map(|(name, data)| Some((name, data?))).collect()
+}
+
+/// Packs `entries` as they are, the tar crate refuses to write some of the names tests need.
+pub fn write_raw_patch(path: &Path, entries: &[(String, Vec<u8>)]) {
+    let mut builder = tar::Builder::new(zstd::Encoder::new(fs::File::create(path).unwrap(), 1).unwrap());
+    for (name, data) in entries {
+        let mut header = tar::Header::new_old();
+        header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, data.as_slice()).unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap();
+}
+
+/// Every entry of the patch at `path`, for tests rewriting some of them with [`write_raw_patch`].
+pub fn read_raw_patch(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(fs::File::open(path).unwrap()).unwrap());
+    archive.entries().unwrap().map(|entry| {
+        let mut entry = entry.unwrap();
+        let name = entry.path().unwrap().to_str().unwrap().to_string();
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+        (name, data)
+    }).collect()
+}
@@ -0,0 +1,70 @@
+//! Applies run against an explicit target directory, several can run at once in one process.
+
+mod common;
+
+use common::{files, path, write, Fixture};
+use patchini::{apply_patch, ApplyOptions, CancelToken, CreateOptions, Event};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::thread;
+
+/// Builds the old and new trees and the patch between them once for every test.
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| Fixture::new("concurrent", &CreateOptions::default(), |old, new| {
+        write(old, "same.txt", b"unchanged");
+        write(old, "sub/changed.bin", &(0..50_000u32).flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>());
+        write(old, "sub/removed.txt", b"removed");
+        write(new, "same.txt", b"unchanged");
+        write(new, "sub/changed.bin", &(0..50_000u32).flat_map(|x| (x ^ 7).to_le_bytes()).collect::<Vec<_>>());
+        write(new, "added/deep/added.txt", b"added");
+    }))
+}
+
+fn target(name: &str) -> PathBuf {
+    fixture().target(name)
+}
+
+fn apply(target: &Path) {
+    fixture().apply(target, &ApplyOptions::default()).unwrap();
+}
+
+#[test]
+fn apply_leaves_current_dir_alone() {
+    let before = std::env::current_dir().unwrap();
+    let target = target("current_dir");
+    apply(&target);
+    assert_eq!(std::env::current_dir().unwrap(), before);
+    assert_eq!(files(&target), files(&fixture().new));
+}
+
+#[test]
+fn concurrent_applies() {
+    let targets: Vec<PathBuf> = (0..4).map(|i| target(&format!("concurrent_{i}"))).collect();
+    thread::scope(|scope| {
+        for target in &targets {
+            scope.spawn(|| apply(target));
+        }
+    });
+    for target in &targets {
+        assert_eq!(files(target), files(&fixture().new));
+    }
+}
+
+#[test]
+fn reentrant_apply() {
+    let outer = target("reentrant_outer");
+    let inner = target("reentrant_inner");
+    let mut nested = false;
+    // Starts a second apply from the progress callback of the first one
+    let mut progress = |event: &Event| {
+        if !nested && matches!(event, Event::FileStarted { .. }) {
+            nested = true;
+            apply(&inner);
+        }
+    };
+    apply_patch(path(&outer), path(&fixture().patch), &ApplyOptions::default(), &mut progress, &CancelToken::new()).unwrap();
+    assert!(nested);
+    assert_eq!(files(&outer), files(&fixture().new));
+    assert_eq!(files(&inner), files(&fixture().new));
+}
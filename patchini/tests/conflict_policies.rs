@@ -0,0 +1,143 @@
+//! Files modified locally are dealt with as the chosen conflict policy says, by default or for the
+//! files matching a glob rule.
+
+mod common;
+
+use common::{files, path, write, Fixture};
+use patchini::{dry_run_patch, ApplyOptions, CancelToken, ConflictPolicy, CreateOptions, Error, Event};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// `hybrid.txt` has a full copy in the patch, `plain.txt` doesn't.
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| {
+        let options = CreateOptions { full_copy_globs: vec!["hybrid.txt".to_string()], ..Default::default() };
+        Fixture::new("conflicts", &options, |old, new| {
+            write(old, "hybrid.txt", b"hybrid v1");
+            write(old, "plain.txt", b"plain v1");
+            write(old, "sub/removed.txt", b"removed");
+            write(new, "hybrid.txt", b"hybrid v2");
+            write(new, "plain.txt", b"plain v2");
+        })
+    })
+}
+
+/// A copy of the old tree named `name` with `modified` changed locally.
+fn target(name: &str, modified: &str) -> PathBuf {
+    let target = fixture().target(name);
+    write(&target, modified, b"edited locally");
+    target
+}
+
+fn apply(target: &Path, options: &ApplyOptions) -> patchini::Result<()> {
+    fixture().apply(target, options)
+}
+
+fn policy(conflicts: ConflictPolicy) -> ApplyOptions {
+    ApplyOptions { conflicts, ..Default::default() }
+}
+
+/// The new tree with `modified` left as it was edited locally.
+fn new_except(modified: &str) -> BTreeMap<String, Vec<u8>> {
+    let mut files = files(&fixture().new);
+    files.insert(modified.to_string(), b"edited locally".to_vec());
+    files
+}
+
+fn conflicts_txt(target: &Path) -> Option<String> {
+    fs::read_to_string(target.join("backup").join("conflicts.txt")).ok()
+}
+
+#[test]
+fn fail_changes_nothing() {
+    let target = target("fail", "hybrid.txt");
+    let before = files(&target);
+    let result = apply(&target, &policy(ConflictPolicy::Fail));
+    assert!(matches!(&result, Err(Error::SourceMismatch { files }) if files == &["hybrid.txt"]), "{result:?}");
+    assert_eq!(files(&target), before);
+}
+
+#[test]
+fn skip_leaves_the_file_alone() {
+    let target = target("skip", "plain.txt");
+    apply(&target, &policy(ConflictPolicy::Skip)).unwrap();
+    assert_eq!(files(&target), new_except("plain.txt"));
+    assert_eq!(conflicts_txt(&target), None);
+}
+
+#[test]
+fn keep_lists_the_file() {
+    let target = target("keep", "plain.txt");
+    apply(&target, &policy(ConflictPolicy::Keep)).unwrap();
+    assert_eq!(files(&target), new_except("plain.txt"));
+    assert_eq!(conflicts_txt(&target).as_deref(), Some("plain.txt\n"));
+}
+
+#[test]
+fn overwrite_uses_the_full_copy() {
+    let target = target("overwrite", "hybrid.txt");
+    apply(&target, &policy(ConflictPolicy::Overwrite)).unwrap();
+    assert_eq!(files(&target), files(&fixture().new));
+}
+
+#[test]
+fn overwrite_without_full_copy_fails() {
+    let target = target("overwrite_plain", "plain.txt");
+    let before = files(&target);
+    let result = apply(&target, &policy(ConflictPolicy::Overwrite));
+    assert!(matches!(&result, Err(Error::SourceMismatch { files }) if files == &["plain.txt"]), "{result:?}");
+    assert_eq!(files(&target), before);
+}
+
+#[test]
+fn overwrite_of_removed_file_fails() {
+    let target = target("overwrite_removed", "sub/removed.txt");
+    let result = apply(&target, &policy(ConflictPolicy::Overwrite));
+    assert!(matches!(&result, Err(Error::SourceMismatch { files }) if files == &["sub/removed.txt"]), "{result:?}");
+}
+
+#[test]
+fn glob_rule_overrides_policy() {
+    let target = target("rule", "plain.txt");
+    let options = ApplyOptions { conflicts: ConflictPolicy::Fail, conflict_rules: vec!["*.txt=keep".parse().unwrap()], ..Default::default() };
+    apply(&target, &options).unwrap();
+    assert_eq!(files(&target), new_except("plain.txt"));
+    assert_eq!(conflicts_txt(&target).as_deref(), Some("plain.txt\n"));
+}
+
+#[test]
+fn stale_conflicts_list_is_removed() {
+    let target = target("stale", "plain.txt");
+    write(&target, "backup/conflicts.txt", b"plain.txt\n");
+    fs::write(target.join("plain.txt"), b"plain v1").unwrap();
+    apply(&target, &ApplyOptions::default()).unwrap();
+    assert_eq!(files(&target), files(&fixture().new));
+    assert_eq!(conflicts_txt(&target), None);
+}
+
+#[test]
+fn dry_run_reports_policies() {
+    let target = target("dry_run", "plain.txt");
+    write(&target, "hybrid.txt", b"edited locally");
+    let before = files(&target);
+    let dry_run = |options: &ApplyOptions| {
+        let dry_run = dry_run_patch(path(&target), path(&fixture().patch), options, &mut |_: &Event| {}, &CancelToken::new()).unwrap();
+        dry_run.mismatched.into_iter().map(|x| (x.path, x.policy)).collect::<Vec<_>>()
+    };
+    assert_eq!(dry_run(&ApplyOptions::default()), [("hybrid.txt".to_string(), ConflictPolicy::Overwrite), ("plain.txt".to_string(), ConflictPolicy::Fail)]);
+    assert_eq!(dry_run(&policy(ConflictPolicy::Keep)), [("hybrid.txt".to_string(), ConflictPolicy::Keep), ("plain.txt".to_string(), ConflictPolicy::Keep)]);
+    assert_eq!(files(&target), before);
+}
+
+#[test]
+fn skip_keeps_removed_file_in_subdirectory() {
+    // Removed paths are stored with `/`, they must still match the native ones found mismatched
+    let rm_files = common::read_raw_patch(&fixture().patch).into_iter().find(|(name, _)| name == "rm_files.txt").unwrap().1;
+    assert_eq!(rm_files, b"sub/removed.txt\n");
+    let target = target("skip_removed", "sub/removed.txt");
+    apply(&target, &policy(ConflictPolicy::Skip)).unwrap();
+    assert_eq!(files(&target), new_except("sub/removed.txt"));
+}
@@ -0,0 +1,29 @@
+//! Applies that can't fit on the target volume are refused before anything gets written.
+
+mod common;
+
+use common::{files, has_journal, path, write, write_raw_patch};
+use patchini::{apply_patch, ApplyOptions, CancelToken, Error, Event, FORMAT_VERSION};
+use std::fs;
+
+const HASH: &str = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+
+#[test]
+fn apply_too_big_for_the_volume() {
+    let dir = std::env::temp_dir().join(format!("patchini-space-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let target = dir.join("target");
+    write(&target, "keep.txt", b"keep");
+    // No volume has an exabyte free, the check trusts the sizes in the hash list
+    let huge = 1u64 << 60;
+    let patch = dir.join("huge.patchini");
+    write_raw_patch(&patch, &[
+        ("manifest.txt".to_string(), format!("format_version={FORMAT_VERSION}\n").into_bytes()),
+        ("target_hashes.txt".to_string(), format!("add {HASH} {huge} huge.bin\nadd {HASH} 7 small.txt\n").into_bytes()),
+        ("new_files/small.txt".to_string(), b"outside".to_vec()),
+    ]);
+    let result = apply_patch(path(&target), path(&patch), &ApplyOptions::default(), &mut |_: &Event| {}, &CancelToken::new());
+    assert!(matches!(&result, Err(Error::NotEnoughSpace { needed, available, .. }) if *needed == huge + 7 && available < needed), "{result:?}");
+    assert_eq!(files(&target), [("keep.txt".to_string(), b"keep".to_vec())].into());
+    assert!(!has_journal(&target));
+}
@@ -0,0 +1,84 @@
+//! Added or moved files that already exist are overwritten, left alone or refused as
+//! `ExistingFiles` says, and dry runs report the same.
+
+mod common;
+
+use common::{files, has_journal, path, write, Fixture};
+use patchini::{dry_run_patch, Action, ApplyOptions, CancelToken, CreateOptions, DryRun, Error, Event, ExistingFiles};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// `added.txt` is shipped in the patch, `b/moved.bin` is made from `a/moved.bin`.
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| Fixture::new("existing", &CreateOptions::default(), |old, new| {
+        write(old, "same.txt", b"unchanged");
+        write(old, "a/moved.bin", b"moved content");
+        write(new, "same.txt", b"unchanged");
+        write(new, "added.txt", b"added");
+        write(new, "b/moved.bin", b"moved content");
+    }))
+}
+
+/// A copy of the old tree named `name` that already has both files the patch adds.
+fn target(name: &str) -> PathBuf {
+    let target = fixture().target(name);
+    write(&target, "added.txt", b"mine");
+    write(&target, "b/moved.bin", b"mine too");
+    target
+}
+
+fn options(existing_files: ExistingFiles) -> ApplyOptions {
+    ApplyOptions { existing_files, ..Default::default() }
+}
+
+fn dry_run(target: &Path, options: &ApplyOptions) -> DryRun {
+    dry_run_patch(path(target), path(&fixture().patch), options, &mut |_: &Event| {}, &CancelToken::new()).unwrap()
+}
+
+#[test]
+fn overwrite_backs_them_up() {
+    let target = target("overwrite");
+    let dry_run = dry_run(&target, &options(ExistingFiles::Overwrite));
+    assert_eq!(dry_run.clobbered().collect::<Vec<_>>(), ["added.txt", "b/moved.bin"]);
+    assert!(dry_run.skipped.is_empty() && dry_run.existing.is_empty() && !dry_run.would_fail());
+
+    fixture().apply(&target, &options(ExistingFiles::Overwrite)).unwrap();
+    assert_eq!(files(&target), files(&fixture().new));
+    assert_eq!(fs::read(target.join("backup/new_files/added.txt")).unwrap(), b"mine");
+    assert_eq!(fs::read(target.join("backup/new_files/b/moved.bin")).unwrap(), b"mine too");
+}
+
+#[test]
+fn skip_leaves_them_alone() {
+    let target = target("skip");
+    let dry_run = dry_run(&target, &options(ExistingFiles::Skip));
+    assert_eq!(dry_run.skipped, ["added.txt", "b/moved.bin"]);
+    // Nothing is written, the original of the moved file is only removed
+    let changes: Vec<(Action, &str)> = dry_run.changes.iter().map(|x| (x.action, x.path.as_str())).collect();
+    assert_eq!(changes, [(Action::Remove, "a/moved.bin")]);
+    assert_eq!(dry_run.space_needed, 0);
+    assert!(!dry_run.would_fail());
+
+    fixture().apply(&target, &options(ExistingFiles::Skip)).unwrap();
+    let mut expected = files(&fixture().new);
+    expected.insert("added.txt".to_string(), b"mine".to_vec());
+    expected.insert("b/moved.bin".to_string(), b"mine too".to_vec());
+    assert_eq!(files(&target), expected);
+}
+
+#[test]
+fn fail_changes_nothing() {
+    let target = target("fail");
+    let dry_run = dry_run(&target, &options(ExistingFiles::Fail));
+    assert_eq!(dry_run.existing, ["added.txt", "b/moved.bin"]);
+    assert!(dry_run.clobbered().next().is_none());
+    assert!(dry_run.would_fail());
+
+    let before = files(&target);
+    let result = fixture().apply(&target, &options(ExistingFiles::Fail));
+    assert!(matches!(&result, Err(Error::FileExists { files }) if files == &["added.txt", "b/moved.bin"]), "{result:?}");
+    assert_eq!(files(&target), before);
+    assert!(!has_journal(&target));
+}
@@ -0,0 +1,116 @@
+//! Patches naming files outside the target directory are refused before anything gets written,
+//! moved or read there.
+
+mod common;
+
+use common::{files, has_journal, path, write_raw_patch};
+use patchini::{apply_patch, dry_run_patch, inspect_patch, verify_patch, ApplyOptions, CancelToken, Error, Event};
+use std::fs;
+use std::path::PathBuf;
+
+const HASH: &str = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+
+/// A target directory with a sibling file the patches try to reach.
+struct Setup {
+    dir: PathBuf,
+    target: PathBuf,
+    outside: PathBuf,
+}
+
+fn setup(name: &str) -> Setup {
+    let dir = std::env::temp_dir().join(format!("patchini-hostile-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let target = dir.join("target");
+    fs::create_dir_all(target.join("sub")).unwrap();
+    fs::write(target.join("keep.txt"), b"keep").unwrap();
+    fs::write(target.join("sub").join("nested.txt"), b"nested").unwrap();
+    let outside = dir.join("outside.txt");
+    fs::write(&outside, b"outside").unwrap();
+    Setup { dir, target, outside }
+}
+
+/// Checks every way of reading the patch made from `entries` rejects it as corrupt, and that
+/// applying it leaves both the target and the file next to it alone.
+fn assert_rejected(name: &str, entries: impl FnOnce(&Setup) -> Vec<(String, Vec<u8>)>) {
+    let setup = setup(name);
+    let patch = setup.dir.join("hostile.patchini");
+    write_raw_patch(&patch, &entries(&setup));
+    let before = files(&setup.target);
+
+    let result = apply_patch(path(&setup.target), path(&patch), &ApplyOptions::default(), &mut |_: &Event| {}, &CancelToken::new());
+    assert!(matches!(result, Err(Error::CorruptPatch { .. })), "apply: {result:?}");
+    assert_eq!(files(&setup.target), before);
+    assert_eq!(fs::read(&setup.outside).unwrap(), b"outside");
+    assert!(!has_journal(&setup.target));
+
+    let result = dry_run_patch(path(&setup.target), path(&patch), &ApplyOptions::default(), &mut |_: &Event| {}, &CancelToken::new());
+    assert!(matches!(result, Err(Error::CorruptPatch { .. })), "dry run: {result:?}");
+    let result = verify_patch(path(&patch));
+    assert!(matches!(result, Err(Error::CorruptPatch { .. })), "verify: {result:?}");
+    let result = inspect_patch(path(&patch));
+    assert!(matches!(result, Err(Error::CorruptPatch { .. })), "inspect: {result:?}");
+    let _ = fs::remove_dir_all(&setup.dir);
+}
+
+fn entry(name: impl Into<String>, data: &[u8]) -> (String, Vec<u8>) {
+    (name.into().replace('\\', "/"), data.to_vec())
+}
+
+#[test]
+fn added_file_in_parent_dir() {
+    assert_rejected("added_parent", |_| vec![entry("new_files/../outside.txt", b"pwned")]);
+}
+
+#[test]
+fn added_file_climbing_out_of_subdir() {
+    assert_rejected("added_subdir", |_| vec![
+        entry("new_files/sub/fine.txt", b"fine"),
+        entry("new_files/sub/../../outside.txt", b"pwned"),
+    ]);
+}
+
+#[test]
+fn added_file_with_absolute_path() {
+    assert_rejected("added_absolute", |setup| vec![entry(format!("new_files/{}", setup.outside.display()), b"pwned")]);
+}
+
+#[test]
+fn added_file_with_dot_path() {
+    assert_rejected("added_dot", |_| vec![entry("new_files/./keep.txt", b"pwned")]);
+}
+
+#[test]
+fn diff_in_parent_dir() {
+    assert_rejected("diff_parent", |_| vec![entry("diff_files/../outside.txt.zspatch001", b"\x28\xb5\x2f\xfd")]);
+}
+
+#[test]
+fn diff_with_absolute_path() {
+    assert_rejected("diff_absolute", |setup| vec![entry(format!("diff_files/{}.zspatch001", setup.outside.display()), b"\x28\xb5\x2f\xfd")]);
+}
+
+#[test]
+fn removed_file_in_parent_dir() {
+    // The first line is fine, it still mustn't be moved once the second one is refused
+    assert_rejected("removed_parent", |_| vec![entry("rm_files.txt", b"keep.txt\n../outside.txt\n")]);
+}
+
+#[test]
+fn removed_file_with_absolute_path() {
+    assert_rejected("removed_absolute", |setup| vec![entry("rm_files.txt", format!("keep.txt\n{}\n", setup.outside.display()).as_bytes())]);
+}
+
+#[test]
+fn moved_file_in_parent_dir() {
+    assert_rejected("moved_parent", |_| vec![entry("moved_files.txt", b"keep.txt\n../outside.txt\n")]);
+}
+
+#[test]
+fn hashed_file_in_parent_dir() {
+    assert_rejected("hashed_parent", |_| vec![entry("source_hashes.txt", format!("rm {HASH} 7 ../outside.txt\n").as_bytes())]);
+}
+
+#[test]
+fn hashed_file_with_absolute_path() {
+    assert_rejected("hashed_absolute", |setup| vec![entry("target_hashes.txt", format!("add {HASH} 7 {}\n", setup.outside.display()).as_bytes())]);
+}
@@ -0,0 +1,185 @@
+//! An apply or rollback that dies halfway leaves its journal behind, the next apply or rollback
+//! restores the original tree from it first. An apply that fails leaves the previous one's journal
+//! alone.
+
+mod common;
+
+use common::{has_journal, path, tree, write, Fixture};
+use patchini::{apply_patch, create_patch, rollback_patch, ApplyOptions, CancelToken, CreateOptions, Error, Event};
+use std::fs;
+use std::io::Write;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+
+fn fixture(name: &str) -> Fixture {
+    Fixture::new(&format!("interrupted-{name}"), &CreateOptions::default(), |old, new| {
+        write(old, "same.txt", b"unchanged");
+        write(old, "sub/changed.bin", &(0..50_000u32).flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>());
+        write(old, "sub/other.txt", b"other v1");
+        write(old, "sub/removed.txt", b"removed");
+        write(new, "same.txt", b"unchanged");
+        write(new, "sub/changed.bin", &(0..50_000u32).flat_map(|x| (x ^ 7).to_le_bytes()).collect::<Vec<_>>());
+        write(new, "sub/other.txt", b"other v2");
+        write(new, "added/deep/added.txt", b"added");
+    })
+}
+
+/// Copies the old tree to `name` and applies the patch there, dying without any cleanup on the
+/// `crash_at`th file apply starts. Then tears the journal like a power cut in the middle of
+/// writing its next line would. Returns the target, or `None` once the apply got through.
+fn crash(fixture: &Fixture, name: &str, crash_at: usize) -> Option<PathBuf> {
+    let target = fixture.target(name);
+    let mut started = 0;
+    let mut progress = |event: &Event| {
+        if matches!(event, Event::FileStarted { .. }) {
+            started += 1;
+            if started == crash_at {
+                panic!("simulated crash");
+            }
+        }
+    };
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        apply_patch(path(&target), path(&fixture.patch), &ApplyOptions::default(), &mut progress, &CancelToken::new())
+    }));
+    if result.is_ok() {
+        return None;
+    }
+    let journal = target.join("backup").join("journal.txt.new");
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(&journal).unwrap();
+    file.write_all(b"moved\tsub/chan").unwrap();
+    Some(target)
+}
+
+#[test]
+fn apply_after_crash_rolls_back_first() {
+    let fixture = fixture("apply");
+    let mut crashes = 0;
+    for crash_at in 1.. {
+        let Some(target) = crash(&fixture, &format!("apply_{crash_at}"), crash_at) else { break };
+        crashes += 1;
+        let mut warnings = Vec::new();
+        let mut progress = |event: &Event| {
+            if let Event::Warning(warning) = event {
+                warnings.push(warning.clone());
+            }
+        };
+        apply_patch(path(&target), path(&fixture.patch), &ApplyOptions::default(), &mut progress, &CancelToken::new()).unwrap();
+        assert!(warnings.iter().any(|x| x.contains("interrupted")), "crash at {crash_at}: {warnings:?}");
+        assert_eq!(tree(&target), tree(&fixture.new), "crash at {crash_at}");
+    }
+    assert!(crashes > 5);
+}
+
+#[test]
+fn rollback_after_crash_restores_original() {
+    let fixture = fixture("rollback");
+    let mut crashes = 0;
+    for crash_at in 1.. {
+        let Some(target) = crash(&fixture, &format!("rollback_{crash_at}"), crash_at) else { break };
+        crashes += 1;
+        rollback_patch(path(&target), &mut |_: &Event| {}).unwrap();
+        assert_eq!(tree(&target), tree(&fixture.old), "crash at {crash_at}");
+    }
+    assert!(crashes > 5);
+}
+
+#[test]
+fn rollback_twice() {
+    let fixture = fixture("twice");
+    let target = fixture.target("twice");
+    fixture.apply(&target, &ApplyOptions::default()).unwrap();
+    rollback_patch(path(&target), &mut |_: &Event| {}).unwrap();
+    assert_eq!(tree(&target), tree(&fixture.old));
+    let result = rollback_patch(path(&target), &mut |_: &Event| {});
+    assert!(matches!(result, Err(Error::InvalidArgument(_))), "{result:?}");
+    assert_eq!(tree(&target), tree(&fixture.old));
+}
+
+/// Applies the patch to a copy of the old tree named `name`, then rolls it back dying on the
+/// `crash_at`th restored file. Returns the target, or `None` once the rollback got through.
+fn crash_rollback(fixture: &Fixture, name: &str, crash_at: usize) -> Option<PathBuf> {
+    let target = fixture.target(name);
+    fixture.apply(&target, &ApplyOptions::default()).unwrap();
+    let mut started = 0;
+    let mut progress = |event: &Event| {
+        if matches!(event, Event::FileStarted { .. }) {
+            started += 1;
+            if started == crash_at {
+                panic!("simulated crash");
+            }
+        }
+    };
+    let result = catch_unwind(AssertUnwindSafe(|| rollback_patch(path(&target), &mut progress)));
+    if result.is_ok() {
+        return None;
+    }
+    Some(target)
+}
+
+#[test]
+fn rollback_after_crashed_rollback() {
+    let fixture = fixture("rerollback");
+    let mut crashes = 0;
+    for crash_at in 1.. {
+        let Some(target) = crash_rollback(&fixture, &format!("rerollback_{crash_at}"), crash_at) else { break };
+        crashes += 1;
+        rollback_patch(path(&target), &mut |_: &Event| {}).unwrap();
+        assert_eq!(tree(&target), tree(&fixture.old), "crash at {crash_at}");
+        assert!(!has_journal(&target));
+    }
+    assert!(crashes > 1);
+}
+
+#[test]
+fn apply_after_crashed_rollback() {
+    let fixture = fixture("reapply");
+    let mut crashes = 0;
+    for crash_at in 1.. {
+        let Some(target) = crash_rollback(&fixture, &format!("reapply_{crash_at}"), crash_at) else { break };
+        crashes += 1;
+        fixture.apply(&target, &ApplyOptions::default()).unwrap();
+        assert_eq!(tree(&target), tree(&fixture.new), "crash at {crash_at}");
+    }
+    assert!(crashes > 1);
+}
+
+#[test]
+fn failed_apply_keeps_previous_journal() {
+    let fixture = fixture("previous");
+    // Touches the same files again, so its backups land where the first apply's are
+    let newer = fixture.dir.join("newer");
+    write(&newer, "same.txt", b"unchanged");
+    write(&newer, "sub/changed.bin", &(0..50_000u32).flat_map(|x| (x ^ 9).to_le_bytes()).collect::<Vec<_>>());
+    write(&newer, "sub/other.txt", b"other v3");
+    write(&newer, "added/deep/added.txt", b"added again");
+    let patch = fixture.dir.join("newer.patchini");
+    create_patch(path(&fixture.new), path(&newer), &CreateOptions::default(), path(&patch), &mut |_: &Event| {}, &CancelToken::new()).unwrap();
+
+    let mut cancels = 0;
+    for cancel_at in 1.. {
+        let target = fixture.target(&format!("previous_{cancel_at}"));
+        fixture.apply(&target, &ApplyOptions::default()).unwrap();
+        let cancel = CancelToken::new();
+        let mut started = 0;
+        let mut progress = |event: &Event| {
+            if matches!(event, Event::FileStarted { .. }) {
+                started += 1;
+                if started == cancel_at {
+                    cancel.cancel();
+                }
+            }
+        };
+        let result = apply_patch(path(&target), path(&patch), &ApplyOptions::default(), &mut progress, &cancel);
+        if result.is_ok() {
+            assert_eq!(tree(&target), tree(&newer));
+            rollback_patch(path(&target), &mut |_: &Event| {}).unwrap();
+            assert_eq!(tree(&target), tree(&fixture.new));
+            break;
+        }
+        cancels += 1;
+        assert_eq!(tree(&target), tree(&fixture.new), "cancel at {cancel_at}");
+        rollback_patch(path(&target), &mut |_: &Event| {}).unwrap();
+        assert_eq!(tree(&target), tree(&fixture.old), "cancel at {cancel_at}");
+    }
+    assert!(cancels > 3);
+}
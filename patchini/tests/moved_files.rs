@@ -0,0 +1,117 @@
+//! Added files with the content of a removed one are moved or copied locally instead of being
+//! shipped in the patch.
+
+mod common;
+
+use common::{path, tree, write, Fixture};
+use patchini::{dry_run_patch, inspect_patch, rollback_patch, Action, ApplyOptions, CancelToken, ConflictPolicy, CreateOptions, Error, Event};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Data that doesn't compress, so the patch would be big if it shipped it.
+fn noise(seed: u32, len: usize) -> Vec<u8> {
+    let mut x = seed;
+    (0..len).map(|_| {
+        x = x.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (x >> 24) as u8
+    }).collect()
+}
+
+/// `a/tex.dds` is renamed, `b/model.bin` is renamed and copied, `c/sound.ogg` is renamed too.
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| Fixture::new("moved", &CreateOptions::default(), |old, new| {
+        write(old, "a/tex.dds", &noise(1, 100_000));
+        write(old, "b/model.bin", &noise(2, 100_000));
+        write(old, "c/sound.ogg", &noise(3, 100_000));
+        write(old, "same.txt", b"unchanged");
+        write(new, "textures/tex.dds", &noise(1, 100_000));
+        write(new, "models/model.bin", &noise(2, 100_000));
+        write(new, "models/model_lod.bin", &noise(2, 100_000));
+        write(new, "sounds/sound.ogg", &noise(3, 100_000));
+        write(new, "same.txt", b"unchanged");
+    }))
+}
+
+fn target(name: &str) -> PathBuf {
+    fixture().target(name)
+}
+
+fn apply(target: &Path, options: &ApplyOptions) -> patchini::Result<()> {
+    fixture().apply(target, options)
+}
+
+#[test]
+fn patch_ships_no_moved_bytes() {
+    let inspection = inspect_patch(path(&fixture().patch)).unwrap();
+    assert!(inspection.added.is_empty());
+    let moved: Vec<(&str, &str)> = inspection.moved.iter().map(|x| (x.from.as_str(), x.path.as_str())).collect();
+    assert_eq!(moved, [
+        ("b/model.bin", "models/model.bin"),
+        ("b/model.bin", "models/model_lod.bin"),
+        ("c/sound.ogg", "sounds/sound.ogg"),
+        ("a/tex.dds", "textures/tex.dds"),
+    ]);
+    assert!(fs::metadata(&fixture().patch).unwrap().len() < 10_000);
+}
+
+#[test]
+fn apply_and_rollback() {
+    let target = target("apply");
+    apply(&target, &ApplyOptions::default()).unwrap();
+    let mut expected = tree(&fixture().new);
+    // Removing files leaves their directories behind
+    for dir in ["a", "b", "c"] {
+        expected.insert(dir.to_string(), None);
+    }
+    assert_eq!(tree(&target), expected);
+    rollback_patch(path(&target), &mut |_: &Event| {}).unwrap();
+    assert_eq!(tree(&target), tree(&fixture().old));
+}
+
+#[test]
+fn modified_original_fails() {
+    let target = target("modified");
+    write(&target, "b/model.bin", b"edited locally");
+    let before = tree(&target);
+
+    // It's removed and has no full copy to fall back to
+    let result = apply(&target, &ApplyOptions::default());
+    assert!(matches!(&result, Err(Error::SourceMismatch { files }) if files == &["b/model.bin"]), "{result:?}");
+    assert_eq!(tree(&target), before);
+
+    // Leaving it alone leaves nothing to make the moved files from
+    let skip = ApplyOptions { conflicts: ConflictPolicy::Skip, ..Default::default() };
+    let dry_run = dry_run_patch(path(&target), path(&fixture().patch), &skip, &mut |_: &Event| {}, &CancelToken::new()).unwrap();
+    assert_eq!(dry_run.unmovable, ["models/model.bin", "models/model_lod.bin"]);
+    assert!(dry_run.would_fail());
+    let result = apply(&target, &skip);
+    assert!(matches!(&result, Err(Error::ApplyFailed { files }) if files == &["models/model.bin", "models/model_lod.bin"]), "{result:?}");
+    assert_eq!(tree(&target), before);
+}
+
+#[test]
+fn dry_run_matches_apply() {
+    let target = target("dry_run");
+    write(&target, "textures/tex.dds", b"already there");
+    let dry_run = dry_run_patch(path(&target), path(&fixture().patch), &ApplyOptions::default(), &mut |_: &Event| {}, &CancelToken::new()).unwrap();
+    assert!(!dry_run.would_fail());
+    assert_eq!(dry_run.clobbered().collect::<Vec<_>>(), ["textures/tex.dds"]);
+    let changes: Vec<(Action, &str)> = dry_run.changes.iter().map(|x| (x.action, x.path.as_str())).collect();
+    assert_eq!(changes, [
+        (Action::Move, "models/model.bin"),
+        (Action::Move, "models/model_lod.bin"),
+        (Action::Move, "sounds/sound.ogg"),
+        (Action::Overwrite, "textures/tex.dds"),
+    ]);
+    // Only the copy takes new space, the originals are moved
+    assert_eq!(dry_run.space_needed, 100_000);
+
+    apply(&target, &ApplyOptions::default()).unwrap();
+    assert_eq!(fs::read(target.join("backup/new_files/textures/tex.dds")).unwrap(), b"already there");
+    assert_eq!(fs::read(target.join("textures/tex.dds")).unwrap(), noise(1, 100_000));
+    rollback_patch(path(&target), &mut |_: &Event| {}).unwrap();
+    assert_eq!(fs::read(target.join("textures/tex.dds")).unwrap(), b"already there");
+    assert_eq!(fs::read(target.join("a/tex.dds")).unwrap(), noise(1, 100_000));
+}
@@ -0,0 +1,36 @@
+//! Patches made by a newer Patchini are refused before anything is read past their manifest.
+
+mod common;
+
+use common::{files, path, write, write_raw_patch};
+use patchini::{apply_patch, dry_run_patch, inspect_patch, verify_patch, ApplyOptions, CancelToken, Error, Event, FORMAT_VERSION};
+use std::fs;
+
+fn unsupported<T>(result: &patchini::Result<T>) -> bool {
+    matches!(result, Err(Error::UnsupportedFormat { version, supported: FORMAT_VERSION }) if *version == FORMAT_VERSION + 1)
+}
+
+#[test]
+fn newer_format_is_unsupported() {
+    let dir = std::env::temp_dir().join(format!("patchini-unsupported-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let target = dir.join("target");
+    write(&target, "keep.txt", b"keep");
+    let patch = dir.join("newer.patchini");
+    // Whatever comes after the manifest may mean something else in a newer layout
+    let manifest = format!("format_version={}\n", FORMAT_VERSION + 1);
+    write_raw_patch(&patch, &[
+        ("manifest.txt".to_string(), manifest.into_bytes()),
+        ("rm_files.txt".to_string(), b"keep.txt\n".to_vec()),
+    ]);
+
+    let result = apply_patch(path(&target), path(&patch), &ApplyOptions::default(), &mut |_: &Event| {}, &CancelToken::new());
+    assert!(unsupported(&result), "apply: {result:?}");
+    assert_eq!(files(&target), [("keep.txt".to_string(), b"keep".to_vec())].into());
+    let result = dry_run_patch(path(&target), path(&patch), &ApplyOptions::default(), &mut |_: &Event| {}, &CancelToken::new());
+    assert!(unsupported(&result), "dry run: {result:?}");
+    let result = verify_patch(path(&patch));
+    assert!(unsupported(&result), "verify: {result:?}");
+    let result = inspect_patch(path(&patch));
+    assert!(unsupported(&result), "inspect: {result:?}");
+}
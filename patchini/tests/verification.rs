@@ -0,0 +1,85 @@
+//! Files are checked against the hashes recorded when the patch was made, before apply touches
+//! them and after it wrote them.
+
+mod common;
+
+use common::{files, has_journal, path, read_raw_patch, tree, write, write_raw_patch, Fixture};
+use patchini::{apply_patch, ApplyOptions, CancelToken, CreateOptions, Error, Event};
+use std::sync::OnceLock;
+
+fn fixture() -> &'static Fixture {
+    static FIXTURE: OnceLock<Fixture> = OnceLock::new();
+    FIXTURE.get_or_init(|| Fixture::new("verification", &CreateOptions::default(), |old, new| {
+        write(old, "same.txt", b"unchanged");
+        write(old, "sub/changed.bin", &(0..50_000u32).flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>());
+        write(old, "sub/other.txt", b"other v1");
+        write(old, "sub/removed.txt", b"removed");
+        write(new, "same.txt", b"unchanged");
+        write(new, "sub/changed.bin", &(0..50_000u32).flat_map(|x| (x ^ 7).to_le_bytes()).collect::<Vec<_>>());
+        write(new, "sub/other.txt", b"other v2");
+        write(new, "added/deep/added.txt", b"added");
+    }))
+}
+
+#[test]
+fn modified_source_refuses_apply() {
+    let fixture = fixture();
+    let target = fixture.target("modified_source");
+    // Same size, so only the hash can tell
+    write(&target, "sub/other.txt", b"other vX");
+    write(&target, "sub/removed.txt", b"edited");
+    let before = files(&target);
+    let mut warnings = Vec::new();
+    let mut progress = |event: &Event| {
+        if let Event::Warning(warning) = event {
+            warnings.push(warning.clone());
+        }
+    };
+    let result = apply_patch(path(&target), path(&fixture.patch), &ApplyOptions::default(), &mut progress, &CancelToken::new());
+    assert!(matches!(&result, Err(Error::SourceMismatch { files }) if files == &["sub/other.txt", "sub/removed.txt"]), "{result:?}");
+    assert!(warnings.iter().any(|x| x.contains("sub/other.txt")), "{warnings:?}");
+    assert_eq!(files(&target), before);
+    assert!(!has_journal(&target));
+}
+
+#[test]
+fn missing_source_refuses_apply() {
+    let fixture = fixture();
+    let target = fixture.target("missing_source");
+    std::fs::remove_file(target.join("sub/changed.bin")).unwrap();
+    let before = files(&target);
+    let result = fixture.apply(&target, &ApplyOptions::default());
+    assert!(matches!(&result, Err(Error::SourceMismatch { files }) if files == &["sub/changed.bin"]), "{result:?}");
+    assert_eq!(files(&target), before);
+}
+
+/// Applies a copy of the patch recording a wrong target hash for `file`.
+fn assert_target_mismatch_rolls_back(name: &str, file: &str) {
+    let fixture = fixture();
+    let patch = fixture.dir.join(format!("{name}.patchini"));
+    let mut entries = read_raw_patch(&fixture.patch);
+    let (_, hashes) = entries.iter_mut().find(|(name, _)| name == "target_hashes.txt").unwrap();
+    let text = String::from_utf8(hashes.clone()).unwrap();
+    *hashes = text.lines().map(|line| {
+        let fields: Vec<&str> = line.splitn(4, ' ').collect();
+        let hash = if fields[3] == file { "0".repeat(64) } else { fields[1].to_string() };
+        format!("{} {hash} {} {}\n", fields[0], fields[2], fields[3])
+    }).collect::<String>().into_bytes();
+    write_raw_patch(&patch, &entries);
+
+    let target = fixture.target(name);
+    let result = apply_patch(path(&target), path(&patch), &ApplyOptions::default(), &mut |_: &Event| {}, &CancelToken::new());
+    assert!(matches!(&result, Err(Error::TargetMismatch { path }) if path == file), "{result:?}");
+    assert_eq!(tree(&target), tree(&fixture.old));
+    assert!(!has_journal(&target));
+}
+
+#[test]
+fn wrong_diffed_file_rolls_back() {
+    assert_target_mismatch_rolls_back("wrong_diffed", "sub/changed.bin");
+}
+
+#[test]
+fn wrong_added_file_rolls_back() {
+    assert_target_mismatch_rolls_back("wrong_added", "added/deep/added.txt");
+}
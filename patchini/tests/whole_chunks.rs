@@ -0,0 +1,32 @@
+//! Chunks that diff worse than they compress alone are stored whole, and apply rebuilds them without
+//! the original.
+
+mod common;
+
+use common::{files, Fixture};
+use patchini::{inspect_patch, ApplyOptions, CreateOptions};
+
+/// Bytes that don't compress, from a linear congruential generator seeded with `seed`.
+fn noise(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 56) as u8
+    }).collect()
+}
+
+#[test]
+fn rewritten_file_is_stored_whole() {
+    let fixture = Fixture::new("whole_chunks", &CreateOptions::default(), |old, new| {
+        // The diff's window is sized to the small old file, too small to see the repeats
+        common::write(old, "rewritten.bin", &noise(1, 1024));
+        common::write(new, "rewritten.bin", &noise(2, 16 * 1024).repeat(16));
+    });
+    let inspection = inspect_patch(common::path(&fixture.patch)).unwrap();
+    assert_eq!(inspection.diffed.len(), 1);
+    assert!(inspection.diffed[0].whole_chunks > 0, "{:?}", inspection.diffed[0]);
+
+    let target = fixture.target("target");
+    fixture.apply(&target, &ApplyOptions::default()).unwrap();
+    assert_eq!(files(&target), files(&fixture.new));
+}